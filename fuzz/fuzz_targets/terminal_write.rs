@@ -0,0 +1,40 @@
+//! Feeds arbitrary input to the terminal and serializes the result, as the server does.
+//!
+//! Run with `cargo fuzz run terminal_write` from the repository root.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate base64;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+// the emulator is a binary crate, so its terminal module is included directly
+#[allow(dead_code)]
+#[path = "../../src/terminal/mod.rs"]
+mod terminal;
+
+use terminal::Terminal;
+
+fuzz_target!(|data: &[u8]| {
+    let mut terminal = Terminal::new(40, 12);
+    terminal.allow_resize = true;
+    terminal.allow_title_report = true;
+
+    // write in chunks so sequences are split across calls, as with PTY reads
+    for chunk in data.chunks(61) {
+        terminal.write(&String::from_utf8_lossy(chunk));
+        terminal.take_response();
+        terminal.take_clipboard_writes();
+        terminal.serialize_screen(0.0, false);
+    }
+
+    terminal.serialize_screen(0.0, true);
+    terminal.hyperlinks();
+    terminal.line_sizes();
+    terminal.palette();
+    terminal.title();
+    terminal.cursor();
+    terminal.attributes();
+});
@@ -1,3 +1,4 @@
+extern crate base64;
 extern crate libc;
 extern crate pty;
 extern crate regex;
@@ -42,6 +43,42 @@ fn apply_template(data: &str, variables: &HashMap<String, String>) -> String {
     }))
 }
 
+/// Parses a color variable, which is either a palette index or a hex color like `#rrggbb`.
+fn parse_color_var(value: &str, default: u32) -> u32 {
+    lazy_static! {
+        static ref HEX_COLOR_RE: Regex = RegexBuilder::new(r"^#[\da-f]{6}$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+    }
+
+    if HEX_COLOR_RE.is_match(value) {
+        u32::from_str_radix(&value[1..], 16).unwrap_or(0) + 256
+    } else {
+        value.parse().unwrap_or(default)
+    }
+}
+
+/// Removes bracketed paste end markers from pasted text so it can't end the paste early.
+fn sanitize_paste(data: &str) -> String {
+    let mut data = String::from(data);
+    // removing a marker could join the surrounding text into a new one
+    while data.contains("\x1b[201~") {
+        data = data.replace("\x1b[201~", "");
+    }
+    data
+}
+
+/// Returns pasted text as it should be sent to the application, wrapped in bracketed paste
+/// markers if the application enabled them (mode 2004).
+fn paste_text(data: &str, bracketed: bool) -> String {
+    if bracketed {
+        format!("\x1b[200~{}\x1b[201~", sanitize_paste(data))
+    } else {
+        String::from(data)
+    }
+}
+
 fn decode_2b(data: &str) -> u32 {
     let data: Vec<_> = data.bytes().collect();
     (data[0] as u32 - 1) + (data[1] as u32 - 1) * 127
@@ -61,6 +98,18 @@ struct ServerState {
     prev_title: String,
     prev_cursor: String,
     prev_line_sizes: String,
+    prev_hyperlinks: String,
+    prev_palette: String,
+    prev_default_colors: (u32, u32),
+    /// Whether pasted text should be wrapped in bracketed paste markers.
+    bracketed_paste: bool,
+    /// Whether the application wants focus in/out events.
+    focus_events: bool,
+    /// Clipboard contents most recently supplied by a client.
+    clipboard: String,
+    /// Snapshot of the terminal diagnostics, for the diagnostics endpoint.
+    diagnostics: terminal::diagnostics::Diagnostics,
+    reset_requested: bool,
 }
 
 struct ConnHandler {
@@ -241,9 +290,22 @@ impl ws::Handler for ConnHandler {
                     state.vars["gpio_initial"].bytes().collect(),
                 ))
             }
+            "/api/v1/diagnostics" => {
+                let mut res = ws::Response::new(
+                    200,
+                    "OK",
+                    serde_json::to_string(&state.diagnostics)
+                        .unwrap()
+                        .bytes()
+                        .collect(),
+                );
+                res.headers_mut()
+                    .push(("Content-Type".into(), b"application/json".to_vec()));
+                Ok(res)
+            }
             "/api/v1/clear" => {
-                // may or may not do anything
-                self.shell_in.send(b"\x1bc".to_vec()).unwrap();
+                // the terminal is owned by the main loop, which will pick this up
+                state.reset_requested = true;
                 Ok(Self::redirect("/cfg/term"))
             }
             "/cfg/system/restore_defaults" | "/cfg/system/restore_hard" => {
@@ -290,6 +352,25 @@ impl ws::Handler for ConnHandler {
                         .send(data.bytes().collect::<Vec<_>>())
                         .unwrap();
                 }
+                'c' => {
+                    // clipboard contents, for answering OSC 52 queries
+                    let mut state = self.state.lock().unwrap();
+                    state.clipboard = data.into();
+                }
+                'v' => {
+                    // pasted text
+                    let state = self.state.lock().unwrap();
+                    let text = paste_text(data, state.bracketed_paste);
+                    self.shell_in.send(text.into_bytes()).unwrap();
+                }
+                'f' => {
+                    // focus in or out
+                    let state = self.state.lock().unwrap();
+                    if state.focus_events {
+                        let report: &[u8] = if data == "1" { b"\x1b[I" } else { b"\x1b[O" };
+                        self.shell_in.send(report.to_vec()).unwrap();
+                    }
+                }
                 'b' => unimplemented!("Button presses"),
                 'm' | 'p' | 'r' => {
                     let row = decode_2b(&data[0..2]);
@@ -394,6 +475,14 @@ fn main() {
             prev_title: "".into(),
             prev_cursor: "".into(),
             prev_line_sizes: "".into(),
+            prev_hyperlinks: "".into(),
+            prev_palette: "".into(),
+            prev_default_colors: (0, 0),
+            bracketed_paste: false,
+            focus_events: false,
+            clipboard: "".into(),
+            diagnostics: terminal::diagnostics::Diagnostics::new(),
+            reset_requested: false,
         }));
 
         let state_clone = Arc::clone(&state);
@@ -448,12 +537,43 @@ fn main() {
                 // TODO: consider sending raw bytes
                 let data_str = String::from_utf8_lossy(&data);
                 terminal.write(&data_str);
+
+                let response = terminal.take_response();
+                if !response.is_empty() {
+                    master.write_all(response.as_bytes()).unwrap();
+                }
             }
 
             {
                 let mut state = state.lock().unwrap();
                 let new_clients: Vec<_> = state.new_clients.drain(..).collect();
 
+                if state.reset_requested {
+                    state.reset_requested = false;
+                    terminal.reset(true);
+                }
+
+                terminal.set_default_colors(
+                    state.vars["theme"].parse().unwrap_or(0),
+                    parse_color_var(&state.vars["default_fg"], 7),
+                    parse_color_var(&state.vars["default_bg"], 0),
+                );
+                terminal.set_default_title(&state.vars["title"]);
+                terminal.set_default_cursor_style(state.vars["cursor_shape"].parse().unwrap_or(1));
+                terminal.allow_title_report = state.vars["allow_title_report"] == "1";
+                terminal.allow_resize = state.vars["allow_app_resize"] == "1";
+                terminal.allow_clipboard_read = state.vars["allow_clipboard_read"] == "1";
+                terminal.set_clipboard(&state.clipboard);
+                terminal.fn_alt_mode = state.vars["fn_alt_mode"] == "1";
+                terminal.want_all_fn = state.vars["want_all_fn"] == "1";
+                terminal.font_size = state.vars["font_size"].parse().unwrap_or(12);
+                terminal.log_unhandled = state.vars["log_unhandled"] == "1";
+                state.bracketed_paste = terminal.is_bracketed_paste();
+                state.focus_events = terminal.is_reporting_focus();
+                if terminal.diagnostics().total() != state.diagnostics.total() {
+                    state.diagnostics = terminal.diagnostics().clone();
+                }
+
                 if !new_clients.is_empty() {
                     // TODO: less hacky solution
                     state.prev_attrs = 0;
@@ -463,6 +583,8 @@ fn main() {
                     state.prev_title = "".into();
                     state.prev_cursor = "".into();
                     state.prev_line_sizes = "".into();
+                    state.prev_hyperlinks = "".into();
+                    state.prev_palette = "".into();
                 }
 
                 let update_debug = if heartbeat_time.elapsed().as_secs() > 1 {
@@ -484,6 +606,12 @@ fn main() {
                 let title = terminal.title();
                 let cursor = terminal.cursor();
                 let line_sizes = terminal.line_sizes();
+                let hyperlinks = terminal.hyperlinks();
+
+                let palette = terminal.palette();
+                let default_colors = terminal.default_colors();
+                let palette_changed = palette != state.prev_palette;
+                let default_colors_changed = default_colors != state.prev_default_colors;
 
                 let mut topic_flags = 0;
                 let mut content = String::new();
@@ -510,13 +638,18 @@ fn main() {
 
                     // connection count
                     content.push(terminal::encode_as_code_point(state.clients.len() as u32));
+
+                    // G2 and G3, after the fields the front end already knows about
+                    content.push(terminal.get_code_page(2));
+                    content.push(terminal.get_code_page(3));
                 }
 
                 let size_changed =
                     terminal.width != state.prev_width || terminal.height != state.prev_height;
 
-                if attrs != state.prev_attrs || size_changed {
+                if attrs != state.prev_attrs || size_changed || default_colors_changed {
                     state.prev_attrs = attrs;
+                    state.prev_default_colors = default_colors;
 
                     if size_changed {
                         unsafe {
@@ -533,37 +666,13 @@ fn main() {
                     }
 
                     topic_flags |= TOPIC_CHANGE_SCREEN_OPTS;
-                    content.push('O');
-                    content.push(terminal::encode_as_code_point(terminal.height));
-                    content.push(terminal::encode_as_code_point(terminal.width));
-                    content.push(terminal::encode_as_code_point(
-                        state.vars["theme"].parse().unwrap_or(0),
-                    ));
-
-                    lazy_static! {
-                        static ref HEX_COLOR_RE: Regex = RegexBuilder::new(r"^#[\da-f]{6}$")
-                            .case_insensitive(true)
-                            .build()
-                            .unwrap();
-                    }
+                    content += &terminal.screen_options();
+                }
 
-                    let default_fg = &state.vars["default_fg"];
-                    let default_bg = &state.vars["default_bg"];
-                    content += &if HEX_COLOR_RE.is_match(default_fg) {
-                        terminal::encode_24color(
-                            u32::from_str_radix(&default_fg[1..], 16).unwrap_or(0) + 256,
-                        )
-                    } else {
-                        terminal::encode_24color(default_fg.parse().unwrap_or(7))
-                    };
-                    content += &if HEX_COLOR_RE.is_match(default_bg) {
-                        terminal::encode_24color(
-                            u32::from_str_radix(&default_bg[1..], 16).unwrap_or(0) + 256,
-                        )
-                    } else {
-                        terminal::encode_24color(default_bg.parse().unwrap_or(0))
-                    };
-                    content.push(terminal::encode_as_code_point(attrs));
+                if palette_changed {
+                    topic_flags |= TOPIC_CHANGE_SCREEN_OPTS;
+                    content += &palette;
+                    state.prev_palette = palette;
                 }
 
                 if static_opts != state.prev_static_opts {
@@ -606,6 +715,19 @@ fn main() {
                     state.prev_line_sizes = line_sizes;
                 }
 
+                if hyperlinks != state.prev_hyperlinks {
+                    // sent before the screen so the front end can resolve link IDs
+                    topic_flags |= TOPIC_CHANGE_CONTENT_PART;
+                    content += &hyperlinks;
+                    state.prev_hyperlinks = hyperlinks;
+                }
+
+                for text in terminal.take_clipboard_writes() {
+                    topic_flags |= TOPIC_INTERNAL;
+                    content.push('c');
+                    content += &terminal::encode_terminated(&text);
+                }
+
                 if state_id != state.prev_state_id || terminal.is_rainbow() {
                     let elapsed = start_time.elapsed();
                     let t =
@@ -677,3 +799,30 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paste_end_markers_are_stripped() {
+        assert_eq!(sanitize_paste("a\x1b[201~b"), "ab");
+        assert_eq!(sanitize_paste("\x1b[201~\x1b[201~"), "");
+        assert_eq!(sanitize_paste("\x1b[20\x1b[201~1~rm -rf"), "rm -rf");
+        assert_eq!(sanitize_paste("\x1b[200~a\x1b[201"), "\x1b[200~a\x1b[201");
+    }
+
+    #[test]
+    fn paste_brackets_follow_mode_2004() {
+        assert_eq!(paste_text("a\x1b[201~b", false), "a\x1b[201~b");
+        assert_eq!(paste_text("a\x1b[201~b", true), "\x1b[200~ab\x1b[201~");
+        assert_eq!(paste_text("", true), "\x1b[200~\x1b[201~");
+
+        // the mode comes from the terminal
+        let mut terminal = terminal::Terminal::new(10, 3);
+        terminal.write("\x1b[?2004h");
+        assert!(terminal.is_bracketed_paste());
+        terminal.write("\x1b[?2004l");
+        assert!(!terminal.is_bracketed_paste());
+    }
+}
@@ -151,7 +151,6 @@ pub const CODE_PAGE_1: CodePageData = CodePageData {
 };
 
 // blocks, thick and split lines, line butts
-#[allow(dead_code)]
 pub const CODE_PAGE_2: CodePageData = CodePageData {
     begin: 33,
     end: 126,
@@ -254,7 +253,6 @@ pub const CODE_PAGE_2: CodePageData = CodePageData {
 };
 
 // dashed lines, split straight lines
-#[allow(dead_code)]
 pub const CODE_PAGE_3: CodePageData = CodePageData {
     begin: 33,
     end: 48,
@@ -277,3 +275,61 @@ pub const CODE_PAGE_3: CodePageData = CodePageData {
         '╿', // 0x,  48  0
     ],
 };
+
+/// ASCII characters replaced by the national replacement character sets, in table order.
+const NATIONAL_POSITIONS: [char; 12] = ['#', '@', '[', '\\', ']', '^', '_', '`', '{', '|', '}', '~'];
+
+pub const NATIONAL_UK: [char; 12] = ['£', '@', '[', '\\', ']', '^', '_', '`', '{', '|', '}', '~'];
+pub const NATIONAL_DUTCH: [char; 12] = ['£', '¾', 'ÿ', '½', '|', '^', '_', '`', '¨', 'f', '¼', '´'];
+pub const NATIONAL_FINNISH: [char; 12] = ['#', '@', 'Ä', 'Ö', 'Å', 'Ü', '_', 'é', 'ä', 'ö', 'å', 'ü'];
+pub const NATIONAL_FRENCH: [char; 12] = ['£', 'à', '°', 'ç', '§', '^', '_', '`', 'é', 'ù', 'è', '¨'];
+pub const NATIONAL_FRENCH_CANADIAN: [char; 12] =
+    ['#', 'à', 'â', 'ç', 'ê', 'î', '_', 'ô', 'é', 'ù', 'è', 'û'];
+pub const NATIONAL_GERMAN: [char; 12] = ['#', '§', 'Ä', 'Ö', 'Ü', '^', '_', '`', 'ä', 'ö', 'ü', 'ß'];
+pub const NATIONAL_ITALIAN: [char; 12] = ['£', '§', '°', 'ç', 'é', '^', '_', 'ù', 'à', 'ò', 'è', 'ì'];
+pub const NATIONAL_NORWEGIAN_DANISH: [char; 12] =
+    ['#', 'Ä', 'Æ', 'Ø', 'Å', 'Ü', '_', 'ä', 'æ', 'ø', 'å', 'ü'];
+pub const NATIONAL_SPANISH: [char; 12] = ['£', '§', '¡', 'Ñ', '¿', '^', '_', '`', '°', 'ñ', 'ç', '~'];
+pub const NATIONAL_SWEDISH: [char; 12] = ['#', 'É', 'Ä', 'Ö', 'Å', 'Ü', '_', 'é', 'ä', 'ö', 'å', 'ü'];
+pub const NATIONAL_SWISS: [char; 12] = ['ù', 'à', 'é', 'ç', 'ê', 'î', 'è', 'ô', 'ä', 'ö', 'ü', 'û'];
+
+/// Translates a character using a national replacement character set table.
+pub fn translate_national(table: &[char; 12], c: char) -> char {
+    match NATIONAL_POSITIONS.iter().position(|&x| x == c) {
+        Some(index) => table[index],
+        None => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn national_tables() {
+        let cases: &[(&[char; 12], &[(char, char)])] = &[
+            (&NATIONAL_UK, &[('#', '£'), ('@', '@'), ('~', '~')]),
+            (&NATIONAL_DUTCH, &[('#', '£'), ('@', '¾'), ('|', 'f'), ('~', '´')]),
+            (&NATIONAL_FINNISH, &[('[', 'Ä'), ('`', 'é'), ('~', 'ü')]),
+            (&NATIONAL_FRENCH, &[('#', '£'), ('@', 'à'), ('~', '¨')]),
+            (&NATIONAL_FRENCH_CANADIAN, &[('@', 'à'), ('[', 'â'), ('~', 'û')]),
+            (&NATIONAL_GERMAN, &[('@', '§'), ('[', 'Ä'), ('~', 'ß')]),
+            (&NATIONAL_ITALIAN, &[('#', '£'), ('`', 'ù'), ('~', 'ì')]),
+            (&NATIONAL_NORWEGIAN_DANISH, &[('@', 'Ä'), ('[', 'Æ'), ('}', 'å')]),
+            (&NATIONAL_SPANISH, &[('[', '¡'), ('|', 'ñ'), ('~', '~')]),
+            (&NATIONAL_SWEDISH, &[('@', 'É'), ('^', 'Ü'), ('{', 'ä')]),
+            (&NATIONAL_SWISS, &[('#', 'ù'), ('_', 'è'), ('~', 'û')]),
+        ];
+        for &(table, translations) in cases {
+            for &(from, to) in translations {
+                assert_eq!(translate_national(table, from), to, "{:?}", table);
+            }
+            // only the replaced positions change
+            for c in (0x20u8..0x7F).map(char::from) {
+                if !NATIONAL_POSITIONS.contains(&c) {
+                    assert_eq!(translate_national(table, c), c);
+                }
+            }
+        }
+    }
+}
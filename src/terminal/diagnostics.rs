@@ -0,0 +1,103 @@
+// Bookkeeping for input the terminal doesn't understand
+
+use std::collections::{HashMap, VecDeque};
+
+/// Number of recent unhandled sequences that are kept as samples.
+const MAX_SAMPLES: usize = 32;
+
+/// Maximum length of a sample in bytes; longer sequences (e.g. OSC strings) are truncated.
+const MAX_SAMPLE_LENGTH: usize = 64;
+
+/// Kinds of unhandled input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnhandledKind {
+    Escape,
+    Csi,
+    Mode,
+    Sgr,
+    Osc,
+    Dcs,
+}
+
+impl UnhandledKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnhandledKind::Escape => "escape",
+            UnhandledKind::Csi => "csi",
+            UnhandledKind::Mode => "mode",
+            UnhandledKind::Sgr => "sgr",
+            UnhandledKind::Osc => "osc",
+            UnhandledKind::Dcs => "dcs",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub kind: &'static str,
+    pub sequence: String,
+}
+
+/// Counts of unhandled sequences by kind, along with the most recent ones.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    total: u64,
+    counts: HashMap<&'static str, u64>,
+    /// Oldest first.
+    samples: VecDeque<Sample>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    /// Records an unhandled sequence, also printing it to stderr if `log` is set.
+    pub fn record(&mut self, kind: UnhandledKind, mut sequence: String, log: bool) {
+        if sequence.len() > MAX_SAMPLE_LENGTH {
+            let mut end = MAX_SAMPLE_LENGTH;
+            while !sequence.is_char_boundary(end) {
+                end -= 1;
+            }
+            sequence.truncate(end);
+        }
+
+        if log {
+            eprintln!("Unhandled {}: {:?}", kind.name(), sequence);
+        }
+
+        self.total += 1;
+        *self.counts.entry(kind.name()).or_insert(0) += 1;
+
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            kind: kind.name(),
+            sequence,
+        });
+    }
+
+    /// Total number of unhandled sequences so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_samples_are_truncated() {
+        let mut diagnostics = Diagnostics::new();
+        let long = format!("\x1b]{}", "a".repeat(70000));
+        diagnostics.record(UnhandledKind::Osc, long, false);
+        // truncated at a character boundary
+        let long = format!("\x1b]1{}", "é".repeat(40));
+        diagnostics.record(UnhandledKind::Osc, long, false);
+        assert_eq!(diagnostics.samples[0].sequence.len(), MAX_SAMPLE_LENGTH);
+        assert_eq!(diagnostics.samples[1].sequence.len(), MAX_SAMPLE_LENGTH - 1);
+        assert_eq!(diagnostics.total(), 2);
+    }
+}
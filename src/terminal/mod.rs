@@ -1,15 +1,31 @@
 pub mod charsets;
+pub mod diagnostics;
+pub mod palette;
 pub mod seq_parser;
 
-use self::charsets::{CODE_PAGE_0, CODE_PAGE_1};
-use self::seq_parser::{Action, ClearType, CodePage, LineSize, SeqParser};
+use self::charsets::{
+    translate_national, CODE_PAGE_0, CODE_PAGE_1, CODE_PAGE_2, CODE_PAGE_3, NATIONAL_DUTCH,
+    NATIONAL_FINNISH, NATIONAL_FRENCH, NATIONAL_FRENCH_CANADIAN, NATIONAL_GERMAN, NATIONAL_ITALIAN,
+    NATIONAL_NORWEGIAN_DANISH, NATIONAL_SPANISH, NATIONAL_SWEDISH, NATIONAL_SWISS, NATIONAL_UK,
+};
+use self::diagnostics::Diagnostics;
+use self::palette::{format_color_spec, DynamicColor, Palette};
+use self::seq_parser::{
+    Action, AltBufferMode, ClearType, CodePage, LineSize, Rect, SeqParser, TitleTarget,
+    UnderlineStyle, WindowReport,
+};
+use base64;
+use std::collections::{HashMap, VecDeque};
 use std::{char, f64, mem};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct CellStyle {
-    attrs: u16,
+    attrs: u32,
     fg: u32,
     bg: u32,
+    underline_color: u32,
+    /// Whether selective erases skip the cell (DECSCA). This is not sent to clients.
+    protected: bool,
 }
 
 impl CellStyle {
@@ -18,6 +34,78 @@ impl CellStyle {
             attrs: 0,
             fg: 0,
             bg: 0,
+            underline_color: 0,
+            protected: false,
+        }
+    }
+
+    /// Returns SGR parameters that would recreate this style, as used in DECRQSS replies.
+    fn sgr_params(&self) -> String {
+        let mut params = vec![String::from("0")];
+
+        // attributes in the order of their SGR numbers
+        let flags = [
+            (1 << 2, "1"),
+            (1 << 9, "2"),
+            (1 << 6, "3"),
+            (1 << 5, "5"),
+            (1 << 4, "7"),
+            (1 << 11, "8"),
+            (1 << 7, "9"),
+            (1 << 10, "20"),
+            (1 << 8, "53"),
+        ];
+        for &(flag, param) in &flags {
+            if self.attrs & flag != 0 {
+                params.push(param.into());
+            }
+        }
+
+        if self.attrs & (1 << 3) != 0 {
+            params.push(match (self.attrs >> 12) & 0b111 {
+                0 => String::from("4"),
+                style => format!("4:{}", style + 1),
+            });
+        }
+
+        let color_params = |color: u32, base: u32| {
+            if base != 58 && color < 8 {
+                format!("{}", base - 8 + color)
+            } else if base != 58 && color < 16 {
+                format!("{}", base + 52 + color - 8)
+            } else if color < 256 {
+                format!("{};5;{}", base, color)
+            } else {
+                let color = color - 256;
+                let (r, g, b) = ((color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF);
+                format!("{};2;{};{};{}", base, r, g, b)
+            }
+        };
+        if self.attrs & (1 << 0) != 0 {
+            params.push(color_params(self.fg, 38));
+        }
+        if self.attrs & (1 << 1) != 0 {
+            params.push(color_params(self.bg, 48));
+        }
+        if self.attrs & (1 << 15) != 0 {
+            params.push(color_params(self.underline_color, 58));
+        }
+
+        params.join(";")
+    }
+
+    fn set_underline(&mut self, style: Option<UnderlineStyle>) {
+        // bit 3 marks any underline, bits 12–14 select the style
+        self.attrs &= !((1 << 3) | (0b111 << 12));
+        if let Some(style) = style {
+            let style_bits = match style {
+                UnderlineStyle::Single => 0,
+                UnderlineStyle::Double => 1,
+                UnderlineStyle::Curly => 2,
+                UnderlineStyle::Dotted => 3,
+                UnderlineStyle::Dashed => 4,
+            };
+            self.attrs |= (1 << 3) | (style_bits << 12);
         }
     }
 
@@ -47,24 +135,75 @@ impl CursorState {
             visible: true,
         }
     }
+
+    /// Whether the cursor style is a blinking one. Odd DECSCUSR styles blink and even ones are
+    /// steady, except for 0, which is a blinking block.
+    fn is_blinking(&self) -> bool {
+        self.style == 0 || self.style % 2 == 1
+    }
+
+    /// Switches between the blinking and steady variant of the cursor style (cursor blink mode).
+    fn set_blinking(&mut self, blinking: bool) {
+        if blinking != self.is_blinking() {
+            self.style = match (blinking, self.style) {
+                (false, 0) => 2,
+                (false, style) => style + 1,
+                (true, style) => style - 1,
+            };
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 struct ScreenCell {
     text: char,
     style: CellStyle,
+    /// Interned hyperlink ID, or 0 if the cell isn't part of a link.
+    hyperlink: u32,
 }
 
 impl ScreenCell {
+    /// Returns an erased cell. Erased cells are never protected, whatever the current style.
+    fn blank(style: CellStyle) -> ScreenCell {
+        ScreenCell {
+            text: ' ',
+            style: CellStyle {
+                protected: false,
+                ..style
+            },
+            hyperlink: 0,
+        }
+    }
+
     pub fn set(&mut self, text: char, style: CellStyle) {
         self.text = text;
         self.style = style;
+        self.hyperlink = 0;
+    }
+
+    /// Erases the cell with the given style. Selective erases leave protected cells alone and
+    /// keep the style of the others.
+    fn erase(&mut self, style: CellStyle, selective: bool) {
+        if !selective {
+            *self = ScreenCell::blank(style);
+        } else if !self.style.protected {
+            let style = self.style;
+            self.set(' ', style);
+        }
     }
 }
 
+/// A line that scrolled off the top of the main screen.
+struct ScrollbackLine {
+    cells: Vec<ScreenCell>,
+    wrapped: bool,
+}
+
 struct ScreenBuffer {
     lines: Vec<Vec<ScreenCell>>,
     line_sizes: Vec<LineSize>,
+    /// Whether each line was soft-wrapped into the next one, rather than ending in a line break.
+    wrapped: Vec<bool>,
 }
 
 impl ScreenBuffer {
@@ -72,6 +211,7 @@ impl ScreenBuffer {
         let mut buf = ScreenBuffer {
             lines: Vec::new(),
             line_sizes: Vec::new(),
+            wrapped: Vec::new(),
         };
         buf.clear(width, height, CellStyle::new());
         buf
@@ -80,7 +220,7 @@ impl ScreenBuffer {
     fn make_line(width: usize, style: CellStyle) -> Vec<ScreenCell> {
         let mut line: Vec<ScreenCell> = Vec::with_capacity(width);
         for _ in 0..width {
-            line.push(ScreenCell { text: ' ', style })
+            line.push(ScreenCell::blank(style))
         }
         line
     }
@@ -88,10 +228,12 @@ impl ScreenBuffer {
     fn clear(&mut self, width: usize, height: usize, style: CellStyle) {
         self.lines.clear();
         self.line_sizes.clear();
+        self.wrapped.clear();
 
         for _ in 0..height {
             self.lines.push(ScreenBuffer::make_line(width, style));
             self.line_sizes.push(LineSize::default());
+            self.wrapped.push(false);
         }
     }
 
@@ -108,26 +250,169 @@ impl ScreenBuffer {
             self.line_sizes[y] = old_line_sizes[y];
         }
     }
+
+    /// Resizes the buffer, joining soft-wrapped lines and wrapping them again at the new width.
+    ///
+    /// The cursor is moved so it stays on the same character, and only moves to another row if the
+    /// lines above it are wrapped differently. Lines that no longer fit are moved into the
+    /// scrollback, and lines are only taken back out of it when the screen grows while its content
+    /// reaches the bottom. Line sizes are reset.
+    fn reflow(
+        &mut self,
+        width: usize,
+        height: usize,
+        scrollback: &mut VecDeque<ScrollbackLine>,
+        cursor: &mut CursorState,
+    ) {
+        let blank = ScreenCell::blank(CellStyle::new());
+
+        // empty lines below the cursor would otherwise become part of the content
+        let last_line = self
+            .lines
+            .iter()
+            .rposition(|line| line.iter().any(|cell| *cell != blank))
+            .unwrap_or(0);
+        let old_height = self.lines.len();
+        let screen_lines = (last_line.max(cursor.y as usize) + 1).min(old_height);
+        let screen_line = scrollback.len();
+        let cursor_line = screen_line + cursor.y as usize;
+
+        let mut lines: Vec<(Vec<ScreenCell>, bool)> = scrollback
+            .drain(..)
+            .map(|line| (line.cells, line.wrapped))
+            .collect();
+        let screen = self.lines.drain(..).zip(self.wrapped.drain(..));
+        lines.extend(screen.take(screen_lines));
+        let line_count = lines.len();
+
+        let mut new_lines: Vec<(Vec<ScreenCell>, bool)> = Vec::new();
+        let mut new_cursor = (0, 0);
+        let mut screen_start = 0;
+        let mut logical_line: Vec<ScreenCell> = Vec::new();
+        let mut cursor_offset = None;
+        let mut screen_offset = None;
+
+        for (y, (cells, wrapped)) in lines.into_iter().enumerate() {
+            if y == screen_line {
+                screen_offset = Some(logical_line.len());
+            }
+            if y == cursor_line {
+                cursor_offset = Some(logical_line.len() + cursor.x as usize);
+            }
+            logical_line.extend(cells);
+            if wrapped && y + 1 < line_count {
+                continue;
+            }
+
+            // trim trailing blanks, but keep everything up to the cursor
+            let mut len = logical_line
+                .iter()
+                .rposition(|cell| *cell != blank)
+                .map_or(0, |x| x + 1);
+            if let Some(offset) = cursor_offset {
+                len = len.max(offset).min(logical_line.len());
+            }
+
+            let start = new_lines.len();
+            let rows = ((len + width - 1) / width).max(1);
+            for row in 0..rows {
+                let end = ((row + 1) * width).min(len);
+                let mut cells = logical_line[(row * width).min(end)..end].to_vec();
+                cells.resize(width, blank);
+                new_lines.push((cells, row + 1 < rows));
+            }
+
+            if let Some(offset) = screen_offset.take() {
+                screen_start = start + (offset / width).min(rows - 1);
+            }
+            if let Some(offset) = cursor_offset.take() {
+                new_cursor = if offset / width >= rows {
+                    // the cursor was hanging at the end of the line
+                    (start + rows - 1, width)
+                } else {
+                    (start + offset / width, offset % width)
+                };
+            }
+            logical_line.clear();
+        }
+
+        // keep the top of the screen where it was, unless the lines below it don't fit; the cursor
+        // stays on screen either way
+        let bottom = new_lines.len().saturating_sub(height).min(new_cursor.0);
+        let first_line = if height > old_height && screen_lines == old_height {
+            bottom
+        } else {
+            screen_start.max(bottom)
+        };
+        for (cells, wrapped) in new_lines.drain(..first_line) {
+            scrollback.push_back(ScrollbackLine { cells, wrapped });
+        }
+        while scrollback.len() > MAX_SCROLLBACK {
+            scrollback.pop_front();
+        }
+
+        self.clear(width, height, CellStyle::new());
+        for (y, (cells, wrapped)) in new_lines.into_iter().take(height).enumerate() {
+            self.lines[y] = cells;
+            self.wrapped[y] = wrapped;
+        }
+
+        cursor.x = new_cursor.1 as i32;
+        cursor.y = (new_cursor.0 - first_line) as i32;
+    }
 }
 
 struct TerminalState {
     style: CellStyle,
     cursor: CursorState,
+    /// The cursor saved by DECSC on the active screen. Each screen has its own.
     saved_cursor: CursorState,
     track_mouse: bool,
     rainbow: bool,
     is_alt_buffer: bool,
     alt_buffer: ScreenBuffer,
+    /// The cursor saved on the inactive screen, swapped with `saved_cursor` like the buffers.
+    alt_saved_cursor: CursorState,
+    /// The main screen's cursor as it was when switching to the alternate screen, so the hidden
+    /// main screen can be reflowed on resize.
+    main_cursor: CursorState,
+    /// Whether the main screen's saved cursor was saved when switching to the alternate screen
+    /// with mode 1049, and will be restored when switching back.
+    saved_main_cursor: bool,
+    /// Lines that scrolled off the main screen, oldest first. These aren't shown by the front end
+    /// but are used when reflowing the screen.
+    scrollback: VecDeque<ScrollbackLine>,
     buffer: ScreenBuffer,
     scroll_margin_top: u32,
     scroll_margin_bottom: u32,
     state_id: u32,
     title: String,
+    icon_name: String,
+    /// Saved (icon name, title) pairs, with None for the parts that weren't pushed.
+    title_stack: Vec<(Option<String>, Option<String>)>,
     bell_id: u32,
     bracketed_paste: bool,
     reverse_video: bool,
+    app_cursor_keys: bool,
+    app_keypad: bool,
+    focus_events: bool,
+    /// The character set slot (G0 through G3) shifted into GL.
     charset: u8,
+    /// A slot used for the next printed character only (SS2 or SS3).
+    single_shift: Option<u8>,
     charsets: Vec<CodePage>,
+    /// Whether national replacement character sets may be designated (DECNRCM).
+    national_charsets: bool,
+    /// Whether DECCARA and DECRARA apply to a rectangle rather than a stream of characters
+    /// (DECSACE).
+    rect_attr_extent: bool,
+    last_char: Option<char>,
+    palette: Palette,
+    /// Interned hyperlink URIs; the ID of a link is its index plus one.
+    hyperlinks: Vec<Option<String>>,
+    hyperlink_ids: HashMap<String, u32>,
+    /// The hyperlink ID given to printed characters.
+    hyperlink: u32,
     last_screen: Vec<ScreenCell>,
 }
 
@@ -137,25 +422,61 @@ impl TerminalState {
             style: CellStyle::new(),
             cursor: CursorState::new(),
             saved_cursor: CursorState::new(),
+            alt_saved_cursor: CursorState::new(),
             track_mouse: false,
             rainbow: false,
             is_alt_buffer: false,
             alt_buffer: ScreenBuffer::new(width, height),
+            main_cursor: CursorState::new(),
+            saved_main_cursor: false,
+            scrollback: VecDeque::new(),
             buffer: ScreenBuffer::new(width, height),
             scroll_margin_top: 0,
             scroll_margin_bottom: height as u32,
             state_id: 0,
             title: String::new(),
+            icon_name: String::new(),
+            title_stack: Vec::new(),
             bell_id: 0,
             bracketed_paste: false,
             reverse_video: false,
+            app_cursor_keys: false,
+            app_keypad: false,
+            focus_events: false,
             charset: 0,
-            charsets: vec![CodePage::USASCII, CodePage::USASCII],
+            single_shift: None,
+            charsets: vec![CodePage::USASCII; 4],
+            national_charsets: false,
+            rect_attr_extent: false,
+            last_char: None,
+            palette: Palette::new(0, 7, 0),
+            hyperlinks: Vec::new(),
+            hyperlink_ids: HashMap::new(),
+            hyperlink: 0,
             last_screen: Vec::new(),
         }
     }
 }
 
+/// Maximum number of saved titles (same as xterm).
+const MAX_TITLE_STACK: usize = 10;
+
+/// Maximum number of lines kept in the scrollback.
+const MAX_SCROLLBACK: usize = 1000;
+
+/// Maximum number of interned hyperlinks.
+const MAX_HYPERLINKS: usize = 1024;
+
+/// Returns the value of a terminfo capability for XTGETTCAP.
+fn termcap_value(name: &str) -> Option<&'static str> {
+    match name {
+        "TN" | "name" => Some("xterm-256color"),
+        "Co" | "colors" => Some("256"),
+        "RGB" => Some("8/8/8"),
+        _ => None,
+    }
+}
+
 fn get_rainbow_color(t: f64) -> u32 {
     let r = (t.sin() * 127.0 + 127.0).floor() as u32;
     let g = ((t + 2.0 / 3.0 * f64::consts::PI).sin() * 127.0 + 127.0).floor() as u32;
@@ -174,6 +495,14 @@ pub fn encode_as_code_point(n: u32) -> char {
     }
 }
 
+/// Encodes a string for an update section that ends with `\x01`. Any `\x01` in the string is
+/// dropped, since it would end the section early and let the rest be read as other sections.
+pub fn encode_terminated(text: &str) -> String {
+    let mut result: String = text.chars().filter(|&c| c != '\x01').collect();
+    result.push('\x01');
+    result
+}
+
 pub fn encode_24color(color: u32) -> String {
     let mut result = String::new();
     if color < 256 {
@@ -191,6 +520,32 @@ pub struct Terminal {
     pub height: u32,
     parser: SeqParser,
     state: TerminalState,
+    default_palette: Palette,
+    /// Theme and default foreground and background the default palette was built from.
+    default_colors: (u32, u32, u32),
+    default_title: String,
+    /// Whether applications may read the window title (CSI 20 t and CSI 21 t).
+    pub allow_title_report: bool,
+    /// Whether applications may resize the terminal (CSI 8 t).
+    pub allow_resize: bool,
+    /// Whether applications may read the clipboard (OSC 52).
+    pub allow_clipboard_read: bool,
+    /// Whether function keys should send alternate (VT) sequences.
+    pub fn_alt_mode: bool,
+    /// Whether the front end should send all function keys instead of handling some itself.
+    pub want_all_fn: bool,
+    /// Font size in pixels, used to estimate the size of the text area.
+    pub font_size: u32,
+    /// Whether unhandled sequences should be printed to stderr.
+    pub log_unhandled: bool,
+    /// Cursor style used initially and for DECSCUSR 0.
+    default_cursor_style: u8,
+    /// Clipboard contents last reported by the front end, for answering clipboard reads.
+    clipboard: String,
+    response: String,
+    /// Texts the application asked to copy to the clipboard (OSC 52).
+    clipboard_writes: Vec<String>,
+    diagnostics: Diagnostics,
 }
 
 impl Terminal {
@@ -200,23 +555,169 @@ impl Terminal {
             height,
             parser: SeqParser::new(),
             state: TerminalState::new(width as usize, height as usize),
+            default_palette: Palette::new(0, 7, 0),
+            default_colors: (0, 7, 0),
+            default_title: String::new(),
+            allow_title_report: false,
+            allow_resize: false,
+            allow_clipboard_read: false,
+            fn_alt_mode: true,
+            want_all_fn: false,
+            font_size: 12,
+            log_unhandled: false,
+            default_cursor_style: 1,
+            clipboard: String::new(),
+            response: String::new(),
+            clipboard_writes: Vec::new(),
+            diagnostics: Diagnostics::new(),
+        }
+    }
+
+    pub fn take_clipboard_writes(&mut self) -> Vec<String> {
+        mem::replace(&mut self.clipboard_writes, Vec::new())
+    }
+
+    /// Sets the clipboard contents reported to applications that read the clipboard.
+    pub fn set_clipboard(&mut self, text: &str) {
+        if text != self.clipboard {
+            self.clipboard = text.into();
+        }
+    }
+
+    /// Sets the theme and default colors the palette is reset to.
+    ///
+    /// Does nothing if they haven't changed, so colors set by the application are kept.
+    pub fn set_default_colors(&mut self, theme: u32, fg: u32, bg: u32) {
+        if (theme, fg, bg) != self.default_colors {
+            self.default_colors = (theme, fg, bg);
+            self.default_palette = Palette::new(theme, fg, bg);
+            self.state.palette = self.default_palette.clone();
+        }
+    }
+
+    /// Sets the default cursor style (a DECSCUSR value) and applies it to the cursor if it changed.
+    pub fn set_default_cursor_style(&mut self, style: u8) {
+        let style = if style <= 6 { style } else { 1 };
+        if style != self.default_cursor_style {
+            self.default_cursor_style = style;
+            self.state.cursor.style = style;
         }
     }
 
+    /// Sets the title shown while the application hasn't set one.
+    pub fn set_default_title(&mut self, title: &str) {
+        if title != self.default_title {
+            self.default_title = title.into();
+        }
+    }
+
+    /// Returns the approximate size of a cell in pixels, as (width, height).
+    ///
+    /// The actual size depends on the font used by the front end, so this assumes a typical
+    /// monospace font with an advance of 0.6 em and a line height of 1.2 em.
+    pub fn cell_size(&self) -> (u32, u32) {
+        let width = (self.font_size as f64 * 0.6).round() as u32;
+        let height = (self.font_size as f64 * 1.2).round() as u32;
+        (width.max(1), height.max(1))
+    }
+
+    /// Returns counts and samples of the sequences that couldn't be handled.
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    /// Returns data that should be written back to the application, e.g. replies to queries.
+    pub fn take_response(&mut self) -> String {
+        mem::replace(&mut self.response, String::new())
+    }
+
     pub fn is_cursor_hanging(&self) -> bool {
-        self.state.cursor.x == self.width as i32
+        self.state.cursor.x == self.line_width(self.state.cursor.y) as i32
     }
 
-    pub fn set_alt_buffer(&mut self, enabled: bool) {
-        if enabled != self.state.is_alt_buffer {
-            self.state.is_alt_buffer = enabled;
+    pub fn set_alt_buffer(&mut self, enabled: bool, mode: AltBufferMode) {
+        // 1049 saves the cursor even if the alternate screen is already active (e.g. after 47),
+        // though that only affects the alternate screen's saved cursor
+        if enabled && mode == AltBufferMode::SaveCursor {
+            self.state.saved_cursor = self.state.cursor;
+        }
 
+        if enabled == self.state.is_alt_buffer {
+            return;
+        }
+
+        if enabled {
+            self.state.main_cursor = self.state.cursor;
+            self.state.saved_main_cursor = mode == AltBufferMode::SaveCursor;
+            self.state.is_alt_buffer = true;
             mem::swap(&mut self.state.buffer, &mut self.state.alt_buffer);
+            mem::swap(
+                &mut self.state.saved_cursor,
+                &mut self.state.alt_saved_cursor,
+            );
 
-            if enabled {
+            if mode == AltBufferMode::SaveCursor {
+                self.clear_screen();
+            }
+        } else {
+            if mode != AltBufferMode::Swap {
                 self.clear_screen();
             }
+
+            self.state.is_alt_buffer = false;
+            self.state.saved_main_cursor = false;
+            mem::swap(&mut self.state.buffer, &mut self.state.alt_buffer);
+            mem::swap(
+                &mut self.state.saved_cursor,
+                &mut self.state.alt_saved_cursor,
+            );
+
+            if mode == AltBufferMode::SaveCursor {
+                self.restore_cursor();
+            }
         }
+        // the other buffer may have different line sizes
+        self.clamp_cursor_to_line();
+    }
+
+    /// Restores the saved cursor (DECRC). The cursor style is not part of the saved state.
+    fn restore_cursor(&mut self) {
+        let style = self.state.cursor.style;
+        self.state.cursor = self.state.saved_cursor;
+        self.state.cursor.style = style;
+        self.clamp_cursor();
+    }
+
+    /// Resets the terminal state.
+    ///
+    /// A hard reset (RIS) restores the initial state entirely, including both screen buffers and
+    /// the window title. A soft reset (DECSTR) leaves the screen contents and cursor position
+    /// alone and only resets modes, margins, character sets and the current style.
+    pub fn reset(&mut self, hard: bool) {
+        if hard {
+            let mut state = TerminalState::new(self.width as usize, self.height as usize);
+            // these must keep increasing so clients pick up the change
+            state.state_id = self.state.state_id;
+            state.bell_id = self.state.bell_id;
+            state.last_screen = mem::replace(&mut self.state.last_screen, Vec::new());
+            state.palette = self.default_palette.clone();
+            state.cursor.style = self.default_cursor_style;
+            self.state = state;
+        } else {
+            self.state.style = CellStyle::new();
+            self.state.cursor.visible = true;
+            self.state.saved_cursor = CursorState::new();
+            self.state.app_cursor_keys = false;
+            self.state.app_keypad = false;
+            self.state.scroll_margin_top = 0;
+            self.state.scroll_margin_bottom = self.height;
+            self.state.charset = 0;
+            self.state.single_shift = None;
+            self.state.charsets = vec![CodePage::USASCII; 4];
+            self.state.national_charsets = false;
+            self.state.rect_attr_extent = false;
+        }
+        self.state.state_id += 1;
     }
 
     pub fn clear_screen(&mut self) {
@@ -227,47 +728,73 @@ impl Terminal {
         );
     }
 
-    pub fn clear_line(&mut self, ln: u32, style: CellStyle) {
+    pub fn clear_line(&mut self, ln: u32, style: CellStyle, selective: bool) {
         if ln >= self.height {
             return;
         }
         self.state.buffer.lines[ln as usize]
             .iter_mut()
-            .for_each(|cell| cell.set(' ', style));
+            .for_each(|cell| cell.erase(style, selective));
+        self.state.buffer.wrapped[ln as usize] = false;
     }
 
-    pub fn clear_line_before(&mut self, ln: u32, col: u32, style: CellStyle) {
+    pub fn clear_line_before(&mut self, ln: u32, col: u32, style: CellStyle, selective: bool) {
         if ln >= self.height {
             return;
         }
         let line = &mut self.state.buffer.lines[ln as usize];
-        for x in 0..=col.min(self.width) {
-            line[x as usize].set(' ', style);
+        for x in 0..=col.min(self.width - 1) {
+            line[x as usize].erase(style, selective);
         }
     }
 
-    pub fn clear_line_after(&mut self, ln: u32, col: u32, style: CellStyle) {
+    pub fn clear_line_after(&mut self, ln: u32, col: u32, style: CellStyle, selective: bool) {
         if ln >= self.height || col >= self.width {
             return;
         }
         let line = &mut self.state.buffer.lines[ln as usize];
         for x in col..self.width {
-            line[x as usize].set(' ', style);
+            line[x as usize].erase(style, selective);
         }
+        self.state.buffer.wrapped[ln as usize] = false;
     }
 
     fn copy_line_from_adjacent(&mut self, y: u32, dy: i32) {
         let target = (y as i32) + dy;
         let line;
-        if target < 0 || target as u32 >= self.state.scroll_margin_bottom {
+        let line_size;
+        let wrapped;
+        if target < self.state.scroll_margin_top as i32
+            || target >= self.state.scroll_margin_bottom as i32
+        {
             line = ScreenBuffer::make_line(self.width as usize, self.state.style);
+            line_size = LineSize::Normal;
+            wrapped = false;
         } else {
             line = self.state.buffer.lines[target as usize].clone();
+            line_size = self.state.buffer.line_sizes[target as usize];
+            wrapped = self.state.buffer.wrapped[target as usize];
         }
         self.state.buffer.lines[y as usize] = line;
+        self.state.buffer.line_sizes[y as usize] = line_size;
+        self.state.buffer.wrapped[y as usize] = wrapped;
     }
 
-    pub fn scroll(&mut self, amount: i32, with_cursor: bool) {
+    /// Scrolls the contents of the scroll region up by the given amount (or down, if negative).
+    pub fn scroll(&mut self, amount: i32) {
+        let amount = amount.max(-(self.height as i32)).min(self.height as i32);
+        if amount > 0 && self.state.scroll_margin_top == 0 && !self.state.is_alt_buffer {
+            let bottom = self.state.scroll_margin_bottom as usize;
+            for y in 0..(amount as usize).min(bottom) {
+                self.state.scrollback.push_back(ScrollbackLine {
+                    cells: self.state.buffer.lines[y].clone(),
+                    wrapped: self.state.buffer.wrapped[y],
+                });
+            }
+            while self.state.scrollback.len() > MAX_SCROLLBACK {
+                self.state.scrollback.pop_front();
+            }
+        }
         if amount >= 0 {
             for y in self.state.scroll_margin_top..self.state.scroll_margin_bottom {
                 self.copy_line_from_adjacent(y, amount);
@@ -277,37 +804,117 @@ impl Terminal {
                 self.copy_line_from_adjacent(y, amount);
             }
         }
-        if with_cursor {
-            self.state.cursor.y -= amount;
-            self.clamp_cursor();
+        self.clamp_cursor_to_line();
+    }
+
+    /// Returns the number of columns in a line, which is halved on double width and double height
+    /// lines.
+    pub fn line_width(&self, y: i32) -> u32 {
+        match self.state.buffer.line_sizes.get(y as usize) {
+            Some(LineSize::Normal) | None => self.width,
+            Some(_) => self.width / 2,
+        }
+    }
+
+    /// Moves the cursor to the last column of its line if it's at or past the end, which also
+    /// cancels a pending wrap. Used whenever the cursor changes lines or line sizes move under it,
+    /// since the line may be narrower than the one the cursor's column belonged to.
+    fn clamp_cursor_to_line(&mut self) {
+        let line_width = self.line_width(self.state.cursor.y) as i32;
+        if self.state.cursor.x >= line_width {
+            self.state.cursor.x = line_width - 1;
         }
     }
 
     pub fn clamp_cursor(&mut self) {
-        self.state.cursor.x = self.state.cursor.x.max(0).min(self.width as i32);
-        self.state.cursor.y = self
-            .state
-            .cursor
-            .y
-            .max(0)
-            .min(self.state.scroll_margin_bottom as i32 - 1);
+        self.state.cursor.y = self.state.cursor.y.max(0).min(self.height as i32 - 1);
+        let line_width = self.line_width(self.state.cursor.y) as i32;
+        self.state.cursor.x = self.state.cursor.x.max(0).min(line_width);
+    }
+
+    /// Sets the size of the line the cursor is on (DECDWL, DECDHL and DECSWL).
+    ///
+    /// Characters in the right half of the line are lost when it becomes double width.
+    pub fn set_line_size(&mut self, size: LineSize) {
+        let y = self.state.cursor.y as usize;
+        if y >= self.state.buffer.line_sizes.len() {
+            return;
+        }
+        self.state.buffer.line_sizes[y] = size;
+        let line_width = self.line_width(y as i32) as usize;
+        let style = self.state.style;
+        for cell in &mut self.state.buffer.lines[y][line_width..] {
+            *cell = ScreenCell::blank(style);
+        }
+        self.clamp_cursor();
+    }
+
+    /// Fills the screen with E characters (DECALN).
+    pub fn screen_alignment_test(&mut self) {
+        self.state.scroll_margin_top = 0;
+        self.state.scroll_margin_bottom = self.height;
+        self.state
+            .buffer
+            .clear(self.width as usize, self.height as usize, CellStyle::new());
+        for line in &mut self.state.buffer.lines {
+            for cell in line {
+                cell.set('E', CellStyle::new());
+            }
+        }
+        self.state.cursor.x = 0;
+        self.state.cursor.y = 0;
+    }
+
+    /// Moves the cursor down one line, scrolling the region if it's at the bottom margin (IND).
+    pub fn index(&mut self) {
+        if self.state.cursor.y == self.state.scroll_margin_bottom as i32 - 1 {
+            self.scroll(1);
+        } else if self.state.cursor.y < self.height as i32 - 1 {
+            self.state.cursor.y += 1;
+        }
+        self.clamp_cursor_to_line();
+    }
+
+    /// Moves the cursor up one line, scrolling the region if it's at the top margin (RI).
+    pub fn reverse_index(&mut self) {
+        if self.state.cursor.y == self.state.scroll_margin_top as i32 {
+            self.scroll(-1);
+        } else if self.state.cursor.y > 0 {
+            self.state.cursor.y -= 1;
+        }
+        self.clamp_cursor_to_line();
     }
 
     pub fn new_line(&mut self) {
-        self.state.cursor.y += 1;
-        if self.state.cursor.y >= self.state.scroll_margin_bottom as i32 {
-            self.scroll(1, true);
+        self.index();
+    }
+
+    /// Moves the cursor up or down, stopping at the scroll margins if it starts out between them
+    /// (CUU, CUD, CPL and CNL).
+    fn move_cursor_y(&mut self, amount: i32) {
+        let y = self.state.cursor.y;
+        let top = self.state.scroll_margin_top as i32;
+        let bottom = self.state.scroll_margin_bottom as i32 - 1;
+        self.state.cursor.y += amount;
+        if amount < 0 && y >= top {
+            self.state.cursor.y = self.state.cursor.y.max(top);
+        } else if amount > 0 && y <= bottom {
+            self.state.cursor.y = self.state.cursor.y.min(bottom);
         }
+        self.clamp_cursor();
     }
 
     pub fn write_char(&mut self, c: char) {
-        if self.state.cursor.x >= self.width as i32 {
+        if self.state.cursor.x >= self.line_width(self.state.cursor.y) as i32 {
+            self.state.buffer.wrapped[self.state.cursor.y as usize] = true;
             self.state.cursor.x = 0;
             self.new_line();
         }
+        self.state.last_char = Some(c);
+        let charset = self.state.single_shift.take().unwrap_or(self.state.charset);
         let c = if (c as u32) < 128 {
             // check code page
-            let code_page = self.state.charsets[self.state.charset as usize];
+            let code_page = self.state.charsets[charset as usize];
 
             macro_rules! code_page_lookup {
                 ($cp:expr, $c:expr) => {{
@@ -321,23 +928,112 @@ impl Terminal {
 
             match code_page {
                 CodePage::USASCII => c,
-                CodePage::UK => c, // technically incorrect
                 CodePage::DECSpecialChars => code_page_lookup!(CODE_PAGE_0, c),
                 CodePage::DOS437 => code_page_lookup!(CODE_PAGE_1, c),
+                CodePage::Blocks => code_page_lookup!(CODE_PAGE_2, c),
+                CodePage::LinesExtra => code_page_lookup!(CODE_PAGE_3, c),
+                CodePage::UK => translate_national(&NATIONAL_UK, c),
+                CodePage::Dutch => translate_national(&NATIONAL_DUTCH, c),
+                CodePage::Finnish => translate_national(&NATIONAL_FINNISH, c),
+                CodePage::French => translate_national(&NATIONAL_FRENCH, c),
+                CodePage::FrenchCanadian => translate_national(&NATIONAL_FRENCH_CANADIAN, c),
+                CodePage::German => translate_national(&NATIONAL_GERMAN, c),
+                CodePage::Italian => translate_national(&NATIONAL_ITALIAN, c),
+                CodePage::NorwegianDanish => translate_national(&NATIONAL_NORWEGIAN_DANISH, c),
+                CodePage::Spanish => translate_national(&NATIONAL_SPANISH, c),
+                CodePage::Swedish => translate_national(&NATIONAL_SWEDISH, c),
+                CodePage::Swiss => translate_national(&NATIONAL_SWISS, c),
             }
         } else {
             c
         };
-        self.state.buffer.lines[self.state.cursor.y as usize][self.state.cursor.x as usize]
-            .set(c, self.state.style);
+        let (x, y) = (self.state.cursor.x as usize, self.state.cursor.y as usize);
+        let cell = &mut self.state.buffer.lines[y][x];
+        cell.set(c, self.state.style);
+        cell.hyperlink = self.state.hyperlink;
         self.state.cursor.x += 1;
     }
 
+    /// Sets the hyperlink for subsequently printed characters (OSC 8). An empty URI ends the link.
+    pub fn set_hyperlink(&mut self, uri: String) {
+        if uri.is_empty() {
+            self.state.hyperlink = 0;
+            return;
+        }
+
+        if let Some(&id) = self.state.hyperlink_ids.get(&uri) {
+            self.state.hyperlink = id;
+            return;
+        }
+
+        if self.state.hyperlinks.len() >= MAX_HYPERLINKS {
+            self.collect_hyperlinks();
+        }
+
+        let index = match self.state.hyperlinks.iter().position(|x| x.is_none()) {
+            Some(index) => index,
+            None if self.state.hyperlinks.len() < MAX_HYPERLINKS => {
+                self.state.hyperlinks.push(None);
+                self.state.hyperlinks.len() - 1
+            }
+            None => {
+                // every slot is visible on screen
+                self.state.hyperlink = 0;
+                return;
+            }
+        };
+
+        let id = index as u32 + 1;
+        self.state.hyperlinks[index] = Some(uri.clone());
+        self.state.hyperlink_ids.insert(uri, id);
+        self.state.hyperlink = id;
+    }
+
+    /// Frees interned hyperlinks that are no longer used by any cell.
+    fn collect_hyperlinks(&mut self) {
+        let mut used = vec![false; self.state.hyperlinks.len()];
+        if self.state.hyperlink > 0 {
+            used[self.state.hyperlink as usize - 1] = true;
+        }
+
+        let lines = self
+            .state
+            .buffer
+            .lines
+            .iter()
+            .chain(self.state.alt_buffer.lines.iter())
+            .chain(self.state.scrollback.iter().map(|line| &line.cells));
+        for cell in lines.flat_map(|line| line.iter()) {
+            if cell.hyperlink > 0 {
+                used[cell.hyperlink as usize - 1] = true;
+            }
+        }
+
+        for (index, link) in self.state.hyperlinks.iter_mut().enumerate() {
+            if !used[index] {
+                if let Some(uri) = link.take() {
+                    self.state.hyperlink_ids.remove(&uri);
+                }
+            }
+        }
+    }
+
+    /// Writes the last printed character again (REP).
+    pub fn repeat_char(&mut self, count: u32) {
+        if let Some(c) = self.state.last_char {
+            // more than a screenful would just overwrite itself
+            for _ in 0..count.min(self.width * self.height) {
+                self.write_char(c);
+            }
+        }
+    }
+
     pub fn move_back(&mut self, count: u32) {
         for _ in 0..count {
             if (self.state.cursor.x as i32) - 1 < 0 {
                 if self.state.cursor.y > 0 {
-                    self.state.cursor.x = (self.width - 1) as i32;
+                    let line_width = self.line_width(self.state.cursor.y - 1);
+                    self.state.cursor.x = (line_width - 1) as i32;
                 }
                 self.state.cursor.y -= 1;
             } else {
@@ -348,15 +1044,13 @@ impl Terminal {
     }
 
     pub fn delete_forward(&mut self, count: u32) {
-        let count = count.min(self.width - (self.state.cursor.x as u32));
+        let width = self.line_width(self.state.cursor.y);
+        let count = count.min(width.saturating_sub(self.state.cursor.x as u32));
         let line = &mut self.state.buffer.lines[self.state.cursor.y as usize];
-        for i in (self.state.cursor.x as u32)..self.width {
+        for i in (self.state.cursor.x as u32)..width {
             let x = i + count;
-            if x >= self.width {
-                line[i as usize] = ScreenCell {
-                    text: ' ',
-                    style: self.state.style,
-                };
+            if x >= width {
+                line[i as usize] = ScreenCell::blank(self.state.style);
             } else {
                 line[i as usize] = line[x as usize];
             }
@@ -364,26 +1058,23 @@ impl Terminal {
     }
 
     pub fn erase_forward(&mut self, count: u32) {
-        let end_index = self.width.min(self.state.cursor.x as u32 + count);
+        let width = self.line_width(self.state.cursor.y);
+        let count = count.min(width.saturating_sub(self.state.cursor.x as u32));
+        let end_index = self.state.cursor.x as u32 + count;
         let line = &mut self.state.buffer.lines[self.state.cursor.y as usize];
         for i in (self.state.cursor.x as u32)..end_index {
-            line[i as usize] = ScreenCell {
-                text: ' ',
-                style: self.state.style,
-            };
+            line[i as usize] = ScreenCell::blank(self.state.style);
         }
     }
 
     pub fn insert_blanks(&mut self, count: u32) {
+        let width = self.line_width(self.state.cursor.y) as i32;
+        let count = (count as i32).min((width - self.state.cursor.x).max(0));
         let line = &mut self.state.buffer.lines[self.state.cursor.y as usize];
-        let end_x = self.state.cursor.x + (count as i32) - 1;
-        for i in (self.state.cursor.x..(self.width as i32)).rev() {
-            let x = i - (count as i32);
-            if x < 0 || x < end_x {
-                line[i as usize] = ScreenCell {
-                    text: ' ',
-                    style: self.state.style,
-                };
+        for i in (self.state.cursor.x..width).rev() {
+            let x = i - count;
+            if x < self.state.cursor.x {
+                line[i as usize] = ScreenCell::blank(self.state.style);
             } else {
                 line[i as usize] = line[x as usize];
             }
@@ -400,12 +1091,18 @@ impl Terminal {
         for y in (end_line..self.state.scroll_margin_bottom).rev() {
             self.state.buffer.lines[y as usize] =
                 self.state.buffer.lines[(y - count) as usize].clone();
+            self.state.buffer.line_sizes[y as usize] =
+                self.state.buffer.line_sizes[(y - count) as usize];
+            self.state.buffer.wrapped[y as usize] = self.state.buffer.wrapped[(y - count) as usize];
         }
 
         for y in (self.state.cursor.y as u32)..end_line {
             self.state.buffer.lines[y as usize] =
-                ScreenBuffer::make_line(self.width as usize, self.state.style)
+                ScreenBuffer::make_line(self.width as usize, self.state.style);
+            self.state.buffer.line_sizes[y as usize] = LineSize::Normal;
+            self.state.buffer.wrapped[y as usize] = false;
         }
+        self.clamp_cursor_to_line();
     }
 
     pub fn delete_lines(&mut self, count: u32) {
@@ -413,13 +1110,161 @@ impl Terminal {
             if y + count >= self.state.scroll_margin_bottom {
                 self.state.buffer.lines[y as usize] =
                     ScreenBuffer::make_line(self.width as usize, self.state.style);
+                self.state.buffer.line_sizes[y as usize] = LineSize::Normal;
+                self.state.buffer.wrapped[y as usize] = false;
             } else {
                 self.state.buffer.lines[y as usize] =
                     self.state.buffer.lines[(y + count) as usize].clone();
+                self.state.buffer.line_sizes[y as usize] =
+                    self.state.buffer.line_sizes[(y + count) as usize];
+                self.state.buffer.wrapped[y as usize] =
+                    self.state.buffer.wrapped[(y + count) as usize];
+            }
+        }
+        self.clamp_cursor_to_line();
+    }
+
+    /// Clamps a rectangle to the screen, returning its (top, left, bottom, right) edges if it
+    /// isn't empty.
+    fn clamp_rect(&self, rect: Rect) -> Option<(usize, usize, usize, usize)> {
+        let bottom = rect.bottom.min(self.height);
+        let right = rect.right.min(self.width);
+        if rect.top < bottom && rect.left < right {
+            Some((
+                rect.top as usize,
+                rect.left as usize,
+                bottom as usize,
+                right as usize,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Fills a rectangular area with a character in the current style (DECFRA). Like erased
+    /// characters, the filled ones aren't protected.
+    pub fn fill_rect(&mut self, c: char, rect: Rect) {
+        if let Some((top, left, bottom, right)) = self.clamp_rect(rect) {
+            let style = CellStyle {
+                protected: false,
+                ..self.state.style
+            };
+            for line in &mut self.state.buffer.lines[top..bottom] {
+                for cell in &mut line[left..right] {
+                    cell.set(c, style);
+                }
+            }
+        }
+    }
+
+    /// Erases a rectangular area (DECERA). A selective erase (DECSERA) skips protected characters
+    /// and keeps the attributes of the others.
+    pub fn erase_rect(&mut self, rect: Rect, selective: bool) {
+        if let Some((top, left, bottom, right)) = self.clamp_rect(rect) {
+            let style = self.state.style;
+            for line in &mut self.state.buffer.lines[top..bottom] {
+                for cell in &mut line[left..right] {
+                    if selective {
+                        cell.erase(style, true);
+                    } else {
+                        *cell = ScreenCell::blank(style);
+                    }
+                }
             }
         }
     }
 
+    /// Copies a rectangular area so its top left corner ends up at the given position (DECCRA).
+    /// Parts that would be moved off screen are dropped.
+    pub fn copy_rect(&mut self, rect: Rect, top: u32, left: u32) {
+        if let Some((src_top, src_left, src_bottom, src_right)) = self.clamp_rect(rect) {
+            let area: Vec<Vec<ScreenCell>> = self.state.buffer.lines[src_top..src_bottom]
+                .iter()
+                .map(|line| line[src_left..src_right].to_vec())
+                .collect();
+            for (dy, row) in area.into_iter().enumerate() {
+                let y = top as usize + dy;
+                if y >= self.height as usize {
+                    break;
+                }
+                for (dx, cell) in row.into_iter().enumerate() {
+                    let x = left as usize + dx;
+                    if x >= self.width as usize {
+                        break;
+                    }
+                    self.state.buffer.lines[y][x] = cell;
+                }
+            }
+        }
+    }
+
+    /// Changes the style of every cell in a rectangular area, or in the stream of characters from
+    /// its top left to its bottom right corner, as selected by DECSACE (DECCARA and DECRARA).
+    fn map_rect_styles<F>(&mut self, rect: Rect, f: F)
+    where
+        F: Fn(&mut CellStyle),
+    {
+        if let Some((top, left, bottom, right)) = self.clamp_rect(rect) {
+            let width = self.width as usize;
+            let rectangle = self.state.rect_attr_extent;
+            for y in top..bottom {
+                let start = if rectangle || y == top { left } else { 0 };
+                let end = if rectangle || y == bottom - 1 {
+                    right
+                } else {
+                    width
+                };
+                for cell in &mut self.state.buffer.lines[y][start..end] {
+                    f(&mut cell.style);
+                }
+            }
+        }
+    }
+
+    /// Returns the DECRQM state of a mode: 1 if set, 2 if reset, or 0 if not recognized.
+    fn mode_state(&self, private: bool, mode: u32) -> u8 {
+        let state = |set: bool| if set { 1 } else { 2 };
+
+        if !private {
+            // no ANSI modes are supported
+            return 0;
+        }
+
+        match mode {
+            1 => state(self.state.app_cursor_keys),
+            5 => state(self.state.reverse_video),
+            12 => state(self.state.cursor.is_blinking()),
+            25 => state(self.state.cursor.visible),
+            42 => state(self.state.national_charsets),
+            47 | 1047 | 1049 => state(self.state.is_alt_buffer),
+            66 => state(self.state.app_keypad),
+            1000 => state(self.state.track_mouse),
+            1004 => state(self.state.focus_events),
+            2004 => state(self.state.bracketed_paste),
+            _ => 0,
+        }
+    }
+
+    /// Returns the DECRQSS report for a setting, or None if it's not supported.
+    fn setting_report(&self, setting: &str) -> Option<String> {
+        match setting {
+            "m" => Some(format!("{}m", self.state.style.sgr_params())),
+            "r" => Some(format!(
+                "{};{}r",
+                self.state.scroll_margin_top + 1,
+                self.state.scroll_margin_bottom
+            )),
+            " q" => Some(format!("{} q", self.state.cursor.style)),
+            "\"q" => Some(format!(
+                "{}\"q",
+                if self.state.style.protected { 1 } else { 0 }
+            )),
+            // there are no left and right margins
+            "s" => Some(format!("1;{}s", self.width)),
+            _ => None,
+        }
+    }
+
     fn handle_action(&mut self, action: Action) {
         use self::Action::*;
 
@@ -439,73 +1284,85 @@ impl Terminal {
             }
             MoveCursor(x, y) => {
                 self.state.cursor.x += x;
-                self.state.cursor.y += y;
-                self.clamp_cursor();
+                self.move_cursor_y(y);
             }
             MoveCursorLine(y) => {
                 self.state.cursor.x = 0;
-                self.state.cursor.y += y;
-                self.clamp_cursor();
-            }
-            MoveCursorLineWithScroll(y) => {
-                self.state.cursor.y += y;
-                if self.state.cursor.y < 0 {
-                    let cursor_y = self.state.cursor.y;
-                    self.scroll(cursor_y, true);
-                } else if self.state.cursor.y >= self.height as i32 {
-                    let cursor_y_diff = self.state.cursor.y - (self.height as i32);
-                    self.scroll(cursor_y_diff + 1, true);
-                }
+                self.move_cursor_y(y);
             }
-            ClearScreen(clear_type) => {
+            Index => self.index(),
+            ReverseIndex => self.reverse_index(),
+            ClearScreen(clear_type, selective) => {
                 let cursor_x = self.state.cursor.x as u32;
                 let cursor_y = self.state.cursor.y as u32;
                 let current_style = self.state.style.clone();
 
                 match clear_type {
-                    ClearType::All => self.clear_screen(),
+                    ClearType::All if !selective => self.clear_screen(),
+                    ClearType::All => {
+                        for y in 0..self.height {
+                            self.clear_line(y, current_style.clone(), true);
+                        }
+                    }
                     ClearType::Before => {
-                        self.clear_line_before(cursor_y, cursor_x, current_style.clone());
+                        self.clear_line_before(
+                            cursor_y,
+                            cursor_x,
+                            current_style.clone(),
+                            selective,
+                        );
                         for y in 0..(self.state.cursor.y as u32) {
-                            self.clear_line(y, current_style.clone());
+                            self.clear_line(y, current_style.clone(), selective);
                         }
                     }
                     ClearType::After => {
-                        self.clear_line_after(cursor_y, cursor_x, current_style.clone());
+                        self.clear_line_after(cursor_y, cursor_x, current_style.clone(), selective);
                         for y in ((self.state.cursor.y + 1) as u32)..self.height {
-                            self.clear_line(y, current_style.clone());
+                            self.clear_line(y, current_style.clone(), selective);
                         }
                     }
+                    ClearType::Scrollback => self.state.scrollback.clear(),
                 }
             }
-            ClearLine(clear_type) => {
+            ClearLine(clear_type, selective) => {
                 let cursor_x = self.state.cursor.x as u32;
                 let cursor_y = self.state.cursor.y as u32;
                 let current_style = self.state.style.clone();
 
                 match clear_type {
-                    ClearType::All => self.clear_line(cursor_y, current_style),
-                    ClearType::Before => self.clear_line_before(cursor_y, cursor_x, current_style),
-                    ClearType::After => self.clear_line_after(cursor_y, cursor_x, current_style),
+                    ClearType::All => self.clear_line(cursor_y, current_style, selective),
+                    ClearType::Before => {
+                        self.clear_line_before(cursor_y, cursor_x, current_style, selective)
+                    }
+                    ClearType::After => {
+                        self.clear_line_after(cursor_y, cursor_x, current_style, selective)
+                    }
+                    ClearType::Scrollback => (),
                 }
             }
             InsertLines(count) => self.insert_lines(count),
             DeleteLines(count) => self.delete_lines(count),
             DeleteForward(count) => self.delete_forward(count),
             EraseForward(count) => self.erase_forward(count),
-            Scroll(count) => self.scroll(count, true),
+            Scroll(count) => self.scroll(count),
             InsertBlanks(count) => self.insert_blanks(count),
+            SetCursorStyle(0) => self.state.cursor.style = self.default_cursor_style,
             SetCursorStyle(style) => self.state.cursor.style = style,
+            SetCursorBlink(blinking) => self.state.cursor.set_blinking(blinking),
             SaveCursor => self.state.saved_cursor = self.state.cursor,
-            RestoreCursor => self.state.cursor = self.state.saved_cursor,
+            RestoreCursor => self.restore_cursor(),
             SetCursorVisible(visible) => self.state.cursor.visible = visible,
-            SetAltBuffer(enabled) => self.set_alt_buffer(enabled),
+            SetAltBuffer(enabled, mode) => self.set_alt_buffer(enabled, mode),
             SetScrollMargin(top, bottom) => {
-                self.state.scroll_margin_top = top;
-                self.state.scroll_margin_bottom = if bottom == 0 || bottom > self.height {
+                let bottom = if bottom == 0 || bottom >= self.height {
                     self.height
                 } else {
                     bottom + 1
+                };
+                // the scroll region must be at least two lines tall
+                if top + 1 < bottom {
+                    self.state.scroll_margin_top = top;
+                    self.state.scroll_margin_bottom = bottom;
                 }
             }
             ResetStyle => self.state.style.reset(),
@@ -519,17 +1376,149 @@ impl Terminal {
                 self.state.style.bg = color;
                 self.state.style.attrs |= 1 << 1; // set attr_bg
             }
+            SetColorUnderline(color) => {
+                self.state.style.underline_color = color;
+                self.state.style.attrs |= 1 << 15; // set attr_underline_color
+            }
+            SetUnderline(style) => self.state.style.set_underline(style),
             ResetColorFG => self.state.style.attrs &= !(1 << 0),
             ResetColorBG => self.state.style.attrs &= !(1 << 1),
+            ResetColorUnderline => self.state.style.attrs &= !(1 << 15),
             SetWindowTitle(title) => self.state.title = title,
+            SetIconName(name) => self.state.icon_name = name,
+            PushTitle(target) => {
+                if self.state.title_stack.len() >= MAX_TITLE_STACK {
+                    self.state.title_stack.remove(0);
+                }
+                let icon_name = if target != TitleTarget::Title {
+                    Some(self.state.icon_name.clone())
+                } else {
+                    None
+                };
+                let title = if target != TitleTarget::IconName {
+                    Some(self.state.title.clone())
+                } else {
+                    None
+                };
+                self.state.title_stack.push((icon_name, title));
+            }
+            PopTitle(target) => {
+                if let Some((icon_name, title)) = self.state.title_stack.pop() {
+                    // only what was pushed can be restored
+                    if let (true, Some(icon_name)) = (target != TitleTarget::Title, icon_name) {
+                        self.state.icon_name = icon_name;
+                    }
+                    if let (true, Some(title)) = (target != TitleTarget::IconName, title) {
+                        self.state.title = title;
+                    }
+                }
+            }
+            ReportTitle(target) => {
+                if self.allow_title_report {
+                    let (kind, text) = match target {
+                        TitleTarget::IconName => ('L', self.state.icon_name.clone()),
+                        _ => ('l', self.title()),
+                    };
+                    self.response += &format!("\x1b]{}{}\x1b\\", kind, text);
+                }
+            }
             SetRainbowMode(enabled) => self.state.rainbow = enabled,
+            SetHyperlink(uri) => self.set_hyperlink(uri),
+            SetClipboard(text) => self.clipboard_writes.push(text),
+            QueryClipboard(selection) => {
+                // the reply names the selection that was asked for
+                if self.allow_clipboard_read {
+                    self.response += &format!(
+                        "\x1b]52;{};{}\x1b\\",
+                        selection,
+                        base64::encode(self.clipboard.as_bytes())
+                    );
+                }
+            }
+            SetPaletteColor(index, color) => self.state.palette.colors[index as usize] = color,
+            QueryPaletteColor(index) => {
+                let color = self.state.palette.colors[index as usize];
+                self.response += &format!("\x1b]4;{};{}\x1b\\", index, format_color_spec(color));
+            }
+            ResetPaletteColor(Some(index)) => {
+                self.state.palette.colors[index as usize] =
+                    self.default_palette.colors[index as usize]
+            }
+            ResetPaletteColor(None) => {
+                self.state.palette.colors = self.default_palette.colors.clone()
+            }
+            SetDynamicColor(which, color) => {
+                *self.state.palette.dynamic_mut(which) = color + 256;
+            }
+            QueryDynamicColor(which) => {
+                let color = self
+                    .state
+                    .palette
+                    .resolve(self.state.palette.dynamic(which));
+                let osc_type = match which {
+                    DynamicColor::Foreground => 10,
+                    DynamicColor::Background => 11,
+                    DynamicColor::Cursor => 12,
+                };
+                self.response += &format!("\x1b]{};{}\x1b\\", osc_type, format_color_spec(color));
+            }
+            ResetDynamicColor(which) => {
+                *self.state.palette.dynamic_mut(which) = self.default_palette.dynamic(which);
+            }
             SetReverseVideo(enabled) => self.state.reverse_video = enabled,
             SetBracketedPaste(enabled) => self.state.bracketed_paste = enabled,
+            SetAppCursorKeys(enabled) => self.state.app_cursor_keys = enabled,
+            SetAppKeypad(enabled) => self.state.app_keypad = enabled,
+            SetFocusEvents(enabled) => self.state.focus_events = enabled,
             SetMouseTracking(enabled) => self.state.track_mouse = enabled,
-            SetLineSize(size) => self.state.buffer.line_sizes[self.state.cursor.y as usize] = size,
-            SetCodePage(i, page) => self.state.charsets[i as usize] = page,
-            SetCharSet(i) => self.state.charset = i,
+            SetLineSize(size) => self.set_line_size(size),
+            ScreenAlignmentTest => self.screen_alignment_test(),
+            SetCodePage(_, page) if page.is_national() && !self.state.national_charsets => (),
+            SetCodePage(i, page) => {
+                if let Some(charset) = self.state.charsets.get_mut(i as usize) {
+                    *charset = page;
+                }
+            }
+            FillRect(c, rect) => self.fill_rect(c, rect),
+            EraseRect(rect, selective) => self.erase_rect(rect, selective),
+            CopyRect(rect, top, left) => self.copy_rect(rect, top, left),
+            ChangeRectAttrs(rect, set, clear) => self.map_rect_styles(rect, |style| {
+                style.attrs = (style.attrs | set) & !clear;
+                if clear & (1 << 3) != 0 {
+                    // also clear the underline style
+                    style.attrs &= !(0b111 << 12);
+                }
+            }),
+            ReverseRectAttrs(rect, attrs) => self.map_rect_styles(rect, |style| {
+                style.attrs ^= attrs;
+                if style.attrs & (1 << 3) == 0 {
+                    style.attrs &= !(0b111 << 12);
+                }
+            }),
+            SetRectAttrExtent(rectangle) => self.state.rect_attr_extent = rectangle,
+            SetProtected(protected) => self.state.style.protected = protected,
+            SetNationalCharsets(enabled) => {
+                self.state.national_charsets = enabled;
+                if !enabled {
+                    for charset in &mut self.state.charsets {
+                        if charset.is_national() {
+                            *charset = CodePage::USASCII;
+                        }
+                    }
+                }
+            }
+            SetCharSet(i) => {
+                if (i as usize) < self.state.charsets.len() {
+                    self.state.charset = i;
+                }
+            }
+            SingleShift(i) => {
+                if (i as usize) < self.state.charsets.len() {
+                    self.state.single_shift = Some(i);
+                }
+            }
             Bell => self.state.bell_id += 1,
+            RepeatChar(count) => self.repeat_char(count),
             Backspace => self.move_back(1),
             NewLine => self.new_line(),
             Return => self.state.cursor.x = 0,
@@ -538,21 +1527,82 @@ impl Terminal {
                     self.write_char(character);
                 }
             }
+            ReportWindow(report) => {
+                let (cell_width, cell_height) = self.cell_size();
+                self.response += &match report {
+                    // never iconified
+                    WindowReport::State => String::from("\x1b[1t"),
+                    WindowReport::Position => String::from("\x1b[3;0;0t"),
+                    WindowReport::TextAreaPixels => format!(
+                        "\x1b[4;{};{}t",
+                        self.height * cell_height,
+                        self.width * cell_width
+                    ),
+                    WindowReport::TextAreaChars => {
+                        format!("\x1b[8;{};{}t", self.height, self.width)
+                    }
+                    WindowReport::ScreenChars => format!("\x1b[9;{};{}t", self.height, self.width),
+                };
+            }
+            Resize(_, _) if !self.allow_resize => (),
             Resize(width, height) => {
                 self.state.scroll_margin_bottom =
                     height.saturating_sub(self.height - self.state.scroll_margin_bottom);
+                if self.state.scroll_margin_top + 1 >= self.state.scroll_margin_bottom {
+                    // the scroll region no longer fits
+                    self.state.scroll_margin_top = 0;
+                    self.state.scroll_margin_bottom = height;
+                }
                 self.width = width;
                 self.height = height;
-                self.state
-                    .buffer
-                    .resize_lossy(width as usize, height as usize, self.state.style);
-                self.state.alt_buffer.resize_lossy(
-                    width as usize,
-                    height as usize,
-                    self.state.style,
-                );
+                let (width, height) = (width as usize, height as usize);
+                let state = &mut self.state;
+                if state.is_alt_buffer {
+                    state.buffer.resize_lossy(width, height, state.style);
+                    state.alt_buffer.reflow(
+                        width,
+                        height,
+                        &mut state.scrollback,
+                        &mut state.main_cursor,
+                    );
+                    if state.saved_main_cursor {
+                        // mode 1049 restores this when switching back
+                        state.alt_saved_cursor = state.main_cursor;
+                    }
+                } else {
+                    state
+                        .buffer
+                        .reflow(width, height, &mut state.scrollback, &mut state.cursor);
+                    state.alt_buffer.resize_lossy(width, height, state.style);
+                }
                 self.clamp_cursor();
             }
+            Reset(hard) => self.reset(hard),
+            QueryMode(private, mode) => {
+                self.response += &format!(
+                    "\x1b[{}{};{}$y",
+                    if private { "?" } else { "" },
+                    mode,
+                    self.mode_state(private, mode)
+                );
+            }
+            QueryTermcap(name) => {
+                let hex =
+                    |data: &str| -> String { data.bytes().map(|b| format!("{:02X}", b)).collect() };
+                self.response += &match termcap_value(&name) {
+                    Some(value) => format!("\x1bP1+r{}={}\x1b\\", hex(&name), hex(value)),
+                    None => format!("\x1bP0+r{}\x1b\\", hex(&name)),
+                };
+            }
+            QuerySetting(setting) => {
+                self.response += &match self.setting_report(&setting) {
+                    Some(report) => format!("\x1bP1$r{}\x1b\\", report),
+                    None => String::from("\x1bP0$r\x1b\\"),
+                };
+            }
+            Unhandled(kind, sequence) => {
+                self.diagnostics.record(kind, sequence, self.log_unhandled)
+            }
             Interrupt => (),
             Tab => (),
             DeleteLine => (),
@@ -576,7 +1626,11 @@ impl Terminal {
     }
 
     pub fn title(&self) -> String {
-        self.state.title.clone()
+        if self.state.title.is_empty() {
+            self.default_title.clone()
+        } else {
+            self.state.title.clone()
+        }
     }
 
     pub fn bell_id(&self) -> u32 {
@@ -605,6 +1659,11 @@ impl Terminal {
         cursor
     }
 
+    /// Returns the attribute word of the screen options ('O') update section.
+    ///
+    /// The front end decodes bit 0 as `cursor.visible` and passes bits 2, 3 and 4 to
+    /// `input.setAlts()` as its `cursorsAlt`, `numpadAlt` and `fnAlt` flags. Bit 1 used to be
+    /// `cursor.hanging`, which is now sent with the cursor position instead, so it is left unset.
     pub fn attributes(&self) -> u32 {
         let mut attributes = 0u32;
 
@@ -615,6 +1674,18 @@ impl Terminal {
         if self.state.cursor.visible {
             attributes |= 1;
         }
+        if self.state.app_cursor_keys {
+            attributes |= 1 << 2;
+        }
+        if self.state.app_keypad {
+            attributes |= 1 << 3;
+        }
+        if self.fn_alt_mode {
+            attributes |= 1 << 4;
+        }
+        if self.want_all_fn {
+            attributes |= 1 << 15;
+        }
         if self.state.track_mouse {
             attributes |= 1 << 5;
         }
@@ -630,6 +1701,43 @@ impl Terminal {
         attributes
     }
 
+    /// Returns the current default foreground and background colors.
+    pub fn default_colors(&self) -> (u32, u32) {
+        (self.state.palette.fg, self.state.palette.bg)
+    }
+
+    /// Serializes the screen options ('O') update section: the screen size, the theme, the
+    /// default colors and the attribute word.
+    pub fn screen_options(&self) -> String {
+        let (fg, bg) = self.default_colors();
+        let mut data = String::from("O");
+        data.push(encode_as_code_point(self.height));
+        data.push(encode_as_code_point(self.width));
+        data.push(encode_as_code_point(self.default_colors.0));
+        data += &encode_24color(fg);
+        data += &encode_24color(bg);
+        data.push(encode_as_code_point(self.attributes()));
+        data
+    }
+
+    /// Serializes palette entries that differ from the theme, as (index, color) pairs.
+    pub fn palette(&self) -> String {
+        let mut data = String::new();
+        let mut count = 0;
+
+        for (i, color) in self.state.palette.colors.iter().enumerate() {
+            if *color != self.default_palette.colors[i] {
+                data.push(encode_as_code_point(i as u32));
+                data += &encode_24color(color + 256);
+                count += 1;
+            }
+        }
+
+        data.insert(0, encode_as_code_point(count));
+        data.insert(0, 'p');
+        data
+    }
+
     pub fn current_code_page(&self) -> u32 {
         self.state.charset as u32
     }
@@ -638,6 +1746,14 @@ impl Terminal {
         self.state.charsets[i].as_char()
     }
 
+    pub fn is_bracketed_paste(&self) -> bool {
+        self.state.bracketed_paste
+    }
+
+    pub fn is_reporting_focus(&self) -> bool {
+        self.state.focus_events
+    }
+
     pub fn is_tracking_mouse(&self) -> bool {
         self.state.track_mouse
     }
@@ -735,6 +1851,7 @@ impl Terminal {
         data.push(encode_as_code_point((right - left) as u32));
 
         let mut last_style = CellStyle::new();
+        let mut last_hyperlink = 0;
 
         for y in top..bottom {
             for x in left..right {
@@ -744,6 +1861,8 @@ impl Terminal {
                         fg: get_rainbow_color(((x + y) as f64) / 10.0 + time),
                         bg: 0,
                         attrs: cell.style.attrs | 3,
+                        underline_color: cell.style.underline_color,
+                        protected: cell.style.protected,
                     }
                 } else {
                     cell.style.clone()
@@ -753,6 +1872,7 @@ impl Terminal {
                     let set_fg = style.fg != last_style.fg;
                     let set_bg = style.bg != last_style.bg;
                     let set_attrs = style.attrs != last_style.attrs;
+                    let set_underline_color = style.underline_color != last_style.underline_color;
 
                     if set_fg && set_bg {
                         if style.has_short_color() {
@@ -772,13 +1892,25 @@ impl Terminal {
                         data.push_str(&encode_24color(style.bg));
                     }
 
+                    if set_underline_color {
+                        data.push('\x07');
+                        data.push_str(&encode_24color(style.underline_color));
+                    }
+
                     if set_attrs {
                         data.push('\x04');
-                        data.push(encode_as_code_point(style.attrs as u32));
+                        data.push(encode_as_code_point(style.attrs));
                     }
 
                     last_style = style
                 }
+
+                if cell.hyperlink != last_hyperlink {
+                    data.push('\x08');
+                    data.push(encode_as_code_point(cell.hyperlink));
+                    last_hyperlink = cell.hyperlink;
+                }
+
                 data.push(cell.text);
             }
         }
@@ -786,6 +1918,30 @@ impl Terminal {
         data
     }
 
+    /// Serializes the URIs of hyperlinks visible on screen, as (id, URI) pairs.
+    pub fn hyperlinks(&self) -> String {
+        let mut visible = vec![false; self.state.hyperlinks.len()];
+        for cell in self.state.buffer.lines.iter().flat_map(|line| line.iter()) {
+            if cell.hyperlink > 0 {
+                visible[cell.hyperlink as usize - 1] = true;
+            }
+        }
+
+        let mut data = String::new();
+        let mut count = 0;
+        for (index, link) in self.state.hyperlinks.iter().enumerate() {
+            if let (true, Some(uri)) = (visible[index], link) {
+                data.push(encode_as_code_point(index as u32 + 1));
+                data += &encode_terminated(uri);
+                count += 1;
+            }
+        }
+
+        data.insert(0, encode_as_code_point(count));
+        data.insert(0, 'L');
+        data
+    }
+
     pub fn line_sizes(&self) -> String {
         let mut data = String::new();
 
@@ -808,3 +1964,977 @@ impl Terminal {
         data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_text(terminal: &Terminal, y: usize) -> String {
+        terminal.state.buffer.lines[y]
+            .iter()
+            .map(|cell| cell.text)
+            .collect()
+    }
+
+    /// Writes a full line of text, moves to the given column and applies the sequence.
+    fn edit_line(column: u32, sequence: &str) -> String {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("abcdefghij");
+        terminal.write(&format!("\x1b[1;{}H{}", column, sequence));
+        line_text(&terminal, 0)
+    }
+
+    #[test]
+    fn insert_blanks_at_edge_columns() {
+        assert_eq!(edit_line(1, "\x1b[2@"), "  abcdefgh");
+        assert_eq!(edit_line(9, "\x1b[2@"), "abcdefgh  ");
+        assert_eq!(edit_line(10, "\x1b[2@"), "abcdefghi ");
+        assert_eq!(edit_line(1, "\x1b[99@"), "          ");
+    }
+
+    #[test]
+    fn delete_forward_at_edge_columns() {
+        assert_eq!(edit_line(1, "\x1b[2P"), "cdefghij  ");
+        assert_eq!(edit_line(9, "\x1b[2P"), "abcdefgh  ");
+        assert_eq!(edit_line(10, "\x1b[2P"), "abcdefghi ");
+        assert_eq!(edit_line(1, "\x1b[99P"), "          ");
+    }
+
+    #[test]
+    fn erase_forward_at_edge_columns() {
+        assert_eq!(edit_line(1, "\x1b[2X"), "  cdefghij");
+        assert_eq!(edit_line(9, "\x1b[2X"), "abcdefgh  ");
+        assert_eq!(edit_line(10, "\x1b[2X"), "abcdefghi ");
+        assert_eq!(edit_line(1, "\x1b[99X"), "          ");
+    }
+
+    #[test]
+    fn erase_forward_stops_at_double_width_line_end() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("abcdefghij\x1b#6\x1b[1;4H\x1b[7m\x1b[99X");
+        assert_eq!(line_text(&terminal, 0), "abc       ");
+        let line = &terminal.state.buffer.lines[0];
+        assert!(line[..5]
+            .iter()
+            .skip(3)
+            .all(|cell| cell.style.attrs & (1 << 4) != 0));
+        assert!(line[5..]
+            .iter()
+            .all(|cell| cell.style.attrs & (1 << 4) == 0));
+    }
+
+    #[test]
+    fn hyperlink_uris_cannot_end_their_section() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.handle_action(Action::SetHyperlink("http://a/\x01T\x01b".into()));
+        terminal.write("x\x1b]8;;\x1b\\");
+        assert_eq!(terminal.hyperlinks(), "L\u{2}\u{2}http://a/Tb\x01");
+
+        // C0 controls are dropped from OSC strings before they get here
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b]8;;http://a/\x01T\x1b\\x\x1b]8;;\x1b\\");
+        assert_eq!(terminal.hyperlinks(), "L\u{2}\u{2}http://a/T\x01");
+    }
+
+    #[test]
+    fn attribute_bits() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.fn_alt_mode = false;
+        // cursor visible, buttons and config links, blinking block cursor
+        let base = 1 | (1 << 7) | (1 << 8) | (1 << 9);
+        assert_eq!(terminal.attributes(), base);
+
+        terminal.write("\x1b[?1h");
+        assert_eq!(terminal.attributes(), base | (1 << 2));
+        terminal.write("\x1b[?1l\x1b=");
+        assert_eq!(terminal.attributes(), base | (1 << 3));
+        terminal.write("\x1b>");
+        terminal.fn_alt_mode = true;
+        assert_eq!(terminal.attributes(), base | (1 << 4));
+        terminal.fn_alt_mode = false;
+        terminal.want_all_fn = true;
+        assert_eq!(terminal.attributes(), base | (1 << 15));
+        terminal.want_all_fn = false;
+
+        terminal.write("\x1b[?25l\x1b[?1000h\x1b[?2004h\x1b[?5h\x1b[6 q");
+        assert_eq!(
+            terminal.attributes(),
+            (1 << 5) | (1 << 7) | (1 << 8) | (6 << 9) | (1 << 13) | (1 << 14)
+        );
+    }
+
+    #[test]
+    fn double_width_lines_moving_under_the_cursor() {
+        // LF onto, RI onto and SU moving a double width line under a cursor past its half
+        for input in &[
+            "\x1b[2;1H\x1b#6\x1b[1;70H\n\x1b[P",
+            "\x1b[1;1H\x1b#6\x1b[2;70H\x1bM\x1b[P",
+            "\x1b[1;70H\x1b[2;1H\x1b#6\x1b[1;70H\x1b[S\x1b[P",
+        ] {
+            let mut terminal = Terminal::new(80, 25);
+            terminal.write(input);
+            assert_eq!(terminal.state.cursor.x, 39, "{:?}", input);
+            terminal.write("\x1b[@\x1b[X\x1b[P");
+        }
+    }
+
+    /// A small xorshift generator, so the random input test is reproducible.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Returns a random mix of text, control characters and whole escape sequences.
+    fn random_input(rng: &mut XorShift, count: usize) -> String {
+        const PARAMS: &[&str] = &["", "0", "1", "2", "3", "5", "6", "7", "12", "70", "65535"];
+        const CSI_FINALS: &str = "@ABCDEFGHJKLMPSTXbcdfhlmnpqrstuvxz{";
+        const ESC_FINALS: &str = "DEHMNOc78=>nop\\";
+        let pick = |rng: &mut XorShift, chars: &str| {
+            chars.chars().nth(rng.below(chars.chars().count())).unwrap()
+        };
+
+        let mut input = String::new();
+        for _ in 0..count {
+            match rng.below(10) {
+                0..=2 => input += ["abc", "é", "字", "\u{1f600}", "xyzzy"][rng.below(5)],
+                3 => input.push(pick(rng, "\x07\x08\t\n\x0b\x0c\r\x0e\x0f")),
+                4..=6 => {
+                    input += ["\x1b[", "\u{9b}"][rng.below(2)];
+                    input += ["", "", "?", ">", "="][rng.below(5)];
+                    for i in 0..rng.below(7) {
+                        if i > 0 {
+                            input.push([';', ';', ':'][rng.below(3)]);
+                        }
+                        input += PARAMS[rng.below(PARAMS.len())];
+                    }
+                    input += ["", "", "", "$", " ", "\"", "*", "!"][rng.below(8)];
+                    input.push(pick(rng, CSI_FINALS));
+                }
+                7 => {
+                    input.push('\x1b');
+                    match rng.below(3) {
+                        0 => input.push(pick(rng, ESC_FINALS)),
+                        1 => {
+                            input.push('#');
+                            input.push(pick(rng, "34568"));
+                        }
+                        _ => {
+                            input.push(pick(rng, "()*+"));
+                            input.push(pick(rng, "0125AB<K"));
+                        }
+                    }
+                }
+                8 => {
+                    input += ["\x1b]", "\x1bP", "\x1b_"][rng.below(3)];
+                    input += ["0;", "4;1;", "8;;", "10;", "52;c;", "$q", "+q"][rng.below(7)];
+                    input += ["?", "x", "rgb:1/2/3", "aGk=", "6d", "http://a"][rng.below(6)];
+                    input += ["\x07", "\x1b\\", ""][rng.below(3)];
+                }
+                _ => input.push(char::from_u32(rng.below(0x250) as u32).unwrap_or('?')),
+            }
+        }
+        input
+    }
+
+    #[test]
+    fn random_input_does_not_panic() {
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        for _ in 0..200 {
+            let mut terminal = Terminal::new(20, 8);
+            terminal.allow_resize = true;
+            terminal.allow_title_report = true;
+            for _ in 0..20 {
+                if rng.below(10) == 0 {
+                    let (height, width) = (rng.below(12), rng.below(30));
+                    terminal.write(&format!("\x1b[8;{};{}t", height, width));
+                }
+                let input = random_input(&mut rng, 50);
+                terminal.write(&input);
+                terminal.take_response();
+                terminal.take_clipboard_writes();
+                terminal.serialize_screen(0.0, rng.below(2) == 0);
+            }
+            terminal.hyperlinks();
+            terminal.line_sizes();
+            terminal.palette();
+            terminal.title();
+            terminal.cursor();
+            terminal.attributes();
+        }
+    }
+
+    fn first_char(input: &str) -> char {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write(input);
+        terminal.state.buffer.lines[0][0].text
+    }
+
+    #[test]
+    fn national_charset_designators() {
+        let cases = [
+            ("A", '#', '£'),
+            ("4", '@', '¾'),
+            ("C", '[', 'Ä'),
+            ("5", '[', 'Ä'),
+            ("R", '@', 'à'),
+            ("f", '@', 'à'),
+            ("Q", '[', 'â'),
+            ("9", '[', 'â'),
+            ("K", '~', 'ß'),
+            ("Y", '~', 'ì'),
+            ("E", '[', 'Æ'),
+            ("6", '[', 'Æ'),
+            ("`", '[', 'Æ'),
+            ("Z", '|', 'ñ'),
+            ("H", '@', 'É'),
+            ("7", '@', 'É'),
+            ("=", '#', 'ù'),
+        ];
+        for &(designator, input, expected) in &cases {
+            let on = format!("\x1b[?42h\x1b({}{}", designator, input);
+            assert_eq!(first_char(&on), expected, "{:?}", designator);
+
+            // without DECNRCM, only the UK set can be designated
+            let off = format!("\x1b({}{}", designator, input);
+            let expected_off = if designator == "A" { expected } else { input };
+            assert_eq!(first_char(&off), expected_off, "{:?}", designator);
+
+            // resetting DECNRCM drops designated national sets
+            let reset = format!("\x1b[?42h\x1b({}\x1b[?42l{}", designator, input);
+            assert_eq!(first_char(&reset), expected_off, "{:?}", designator);
+        }
+    }
+
+    #[test]
+    fn national_charsets_in_g2_and_g3() {
+        let mut terminal = Terminal::new(10, 3);
+        // G2 German, G3 French, then SS2, SS3, an unshifted character and 8-bit SS2 and SS3
+        terminal.write("\x1b[?42h\x1b*K\x1b+R\x1bN~\x1bO@~\u{8e}[\u{8f}~");
+        assert_eq!(line_text(&terminal, 0), "ßà~Ä¨     ");
+
+        // locking shifts LS2 and LS3
+        terminal.write("\r\n\x1bn~\x1bo@\x1b(B\x0f~");
+        assert_eq!(line_text(&terminal, 1), "ßà~       ");
+
+        // designations to G2 and G3 are ignored without DECNRCM
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b*K\x1b+R\x1bN~\x1bO@");
+        assert_eq!(line_text(&terminal, 0), "~@        ");
+    }
+
+    #[test]
+    fn resize_in_alternate_screen_reflows_main_cursor() {
+        let text = "0123456789abcdefghijklmno";
+
+        // with 1049, the cursor restored on exit follows the reflowed main screen
+        let mut terminal = Terminal::new(20, 5);
+        terminal.allow_resize = true;
+        terminal.write(text);
+        terminal.write("\x1b[?1049h\x1b[3;3H\x1b[8;5;10t\x1b[?1049l");
+        assert_eq!((terminal.state.cursor.x, terminal.state.cursor.y), (5, 2));
+
+        // with 1047, the saved cursor belongs to neither screen and is left alone
+        let mut terminal = Terminal::new(20, 5);
+        terminal.allow_resize = true;
+        terminal.write("\x1b[2;2H\x1b[s\x1b[1;1H");
+        terminal.write(text);
+        terminal.write("\x1b[?1047h\x1b[8;5;10t");
+        assert_eq!(
+            (terminal.state.main_cursor.x, terminal.state.main_cursor.y),
+            (5, 2)
+        );
+        terminal.write("\x1b[?1047l\x1b[u");
+        assert_eq!((terminal.state.cursor.x, terminal.state.cursor.y), (1, 1));
+    }
+
+    #[test]
+    fn erased_cells_are_not_protected() {
+        // EL, ED, ECH and DECERA with DECSCA 1, then a selective erase of the whole line
+        for erase in &["\x1b[2K", "\x1b[2J", "\x1b[10X", "\x1b[$z"] {
+            let mut terminal = Terminal::new(10, 3);
+            terminal.write(&format!("abc\x1b[1\"q{}\x1b[0\"q\x1b[1;1H\x1b[?2K", erase));
+            assert_eq!(line_text(&terminal, 0), "          ", "{:?}", erase);
+            let line = &terminal.state.buffer.lines[0];
+            assert!(line.iter().all(|cell| !cell.style.protected), "{:?}", erase);
+        }
+
+        // DECFRA fills aren't protected either
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[1\"q\x1b[88;1;1;1;3$x\x1b[0\"q\x1b[?K");
+        assert_eq!(line_text(&terminal, 0), "          ");
+
+        // but printed characters are
+        terminal.write("\x1b[1\"qab\x1b[0\"qc\x1b[1;1H\x1b[?K");
+        assert_eq!(line_text(&terminal, 0), "ab        ");
+    }
+
+    #[test]
+    fn title_stack_targets() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b]1;icon\x07\x1b]2;title\x07\x1b[22;2t\x1b]0;new\x07\x1b[23;0t");
+        assert_eq!(
+            (&*terminal.state.icon_name, &*terminal.state.title),
+            ("new", "title")
+        );
+
+        terminal.write("\x1b[22;0t\x1b]0;newer\x07\x1b[23;1t");
+        assert_eq!(
+            (&*terminal.state.icon_name, &*terminal.state.title),
+            ("new", "newer")
+        );
+    }
+
+    /// Marks the cells of a line that have an attribute bit set.
+    fn attr_marks(terminal: &Terminal, y: usize, attr: u32) -> String {
+        terminal.state.buffer.lines[y]
+            .iter()
+            .map(|cell| {
+                if cell.style.attrs & attr != 0 {
+                    'x'
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn change_rect_attrs() {
+        let bold = 1 << 2;
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[1;6;3;7;1$r");
+        assert_eq!(attr_marks(&terminal, 0, bold), ".....xxxxx");
+        assert_eq!(attr_marks(&terminal, 1, bold), "xxxxxxxxxx");
+        assert_eq!(attr_marks(&terminal, 2, bold), "xxxxxxx...");
+
+        // 22 clears bold, while 21 isn't one of the DECCARA attributes
+        terminal.write("\x1b[3;1;3;3;21$r");
+        assert_eq!(attr_marks(&terminal, 2, bold), "xxxxxxx...");
+        terminal.write("\x1b[3;1;3;3;22$r");
+        assert_eq!(attr_marks(&terminal, 2, bold), "...xxxx...");
+
+        // 0 clears all of them, and later parameters override earlier ones
+        terminal.write("\x1b[2;1;2;10;4;7;0;5$r");
+        let line = &terminal.state.buffer.lines[1];
+        assert!(line.iter().all(|cell| cell.style.attrs == 1 << 5));
+        terminal.write("\x1b[2;1;2;10;25;4:3;27;4$r");
+        let line = &terminal.state.buffer.lines[1];
+        assert!(line.iter().all(|cell| cell.style.attrs == 1 << 3));
+        terminal.write("\x1b[2;1;2;10;24$r");
+        let line = &terminal.state.buffer.lines[1];
+        assert!(line.iter().all(|cell| cell.style.attrs == 0));
+    }
+
+    #[test]
+    fn reverse_rect_attrs() {
+        let (bold, inverse) = (1 << 2, 1 << 4);
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[2*x\x1b[1;1;2;3;1;7$t\x1b[1;2;2;4;7;22$t");
+        assert_eq!(attr_marks(&terminal, 0, bold), "xxx.......");
+        assert_eq!(attr_marks(&terminal, 1, inverse), "x..x......");
+
+        // 0 reverses all of them
+        terminal.write("\x1b[3;1;3;2$t");
+        assert_eq!(terminal.state.buffer.lines[2][0].style.attrs, 0b11_1100);
+    }
+
+    #[test]
+    fn rect_attr_extent() {
+        let bold = 1 << 2;
+        for &(extent, changed) in &[
+            ("", ["....xxxxxx", "xxxxxxxxxx", "xxxxxx...."]),
+            ("\x1b[1*x", ["....xxxxxx", "xxxxxxxxxx", "xxxxxx...."]),
+            ("\x1b[2*x", ["....xx....", "....xx....", "....xx...."]),
+        ] {
+            let mut terminal = Terminal::new(10, 4);
+            terminal.write(&format!("{}\x1b[1;5;3;6;1$r", extent));
+            for (y, expected) in changed.iter().enumerate() {
+                assert_eq!(&attr_marks(&terminal, y, bold), expected, "{:?}", extent);
+            }
+            assert_eq!(attr_marks(&terminal, 3, bold), "..........");
+        }
+
+        // DECSTR returns to the stream extent
+        let mut terminal = Terminal::new(10, 4);
+        terminal.write("\x1b[2*x\x1b[!p\x1b[1;5;2;6;1$r");
+        assert_eq!(attr_marks(&terminal, 1, bold), "xxxxxx....");
+    }
+
+    #[test]
+    fn copy_and_erase_rect() {
+        let mut terminal = Terminal::new(10, 4);
+        terminal.write("abcdefghij\r\nklmnopqrst\x1b[1;2;2;3;1;3;9$v");
+        assert_eq!(line_text(&terminal, 2), "        bc");
+        assert_eq!(line_text(&terminal, 3), "        lm");
+
+        // overlapping copies use the source as it was before the copy
+        terminal.write("\x1b[1;1;2;5;1;1;2$v");
+        assert_eq!(line_text(&terminal, 0), "aabcdeghij");
+        assert_eq!(line_text(&terminal, 1), "kklmnoqrst");
+
+        // DECERA and DECSERA; the selective erase skips protected characters
+        terminal.write("\x1b[2;3;3;99$z");
+        assert_eq!(line_text(&terminal, 1), "kk        ");
+        assert_eq!(line_text(&terminal, 2), "          ");
+        terminal.write("\x1b[1;1H\x1b[1\"qAB\x1b[0\"q\x1b[1;1;1;4${");
+        assert_eq!(line_text(&terminal, 0), "AB  deghij");
+    }
+
+    fn cursor_position(terminal: &Terminal) -> (i32, i32) {
+        (terminal.state.cursor.x, terminal.state.cursor.y)
+    }
+
+    fn screen_text(terminal: &Terminal) -> Vec<String> {
+        (0..terminal.height as usize)
+            .map(|y| line_text(terminal, y).trim_end().to_string())
+            .collect()
+    }
+
+    fn resized(input: &str, width: u32, height: u32, resizes: &[(u32, u32)]) -> Terminal {
+        let mut terminal = Terminal::new(width, height);
+        terminal.allow_resize = true;
+        terminal.write(input);
+        for &(width, height) in resizes {
+            terminal.write(&format!("\x1b[8;{};{}t", height, width));
+        }
+        terminal
+    }
+
+    #[test]
+    fn reflow_width() {
+        // narrowing wraps lines and keeps the cursor on the same character and row
+        let terminal = resized("abcdefghijkl\r\nxy\x1b[D", 20, 5, &[(10, 5)]);
+        assert_eq!(screen_text(&terminal), ["abcdefghij", "kl", "xy", "", ""]);
+        assert_eq!(cursor_position(&terminal), (1, 2));
+
+        // widening joins them again
+        let terminal = resized("abcdefghijkl\r\nxy\x1b[D", 10, 5, &[(20, 5)]);
+        assert_eq!(screen_text(&terminal), ["abcdefghijkl", "xy", "", "", ""]);
+        assert_eq!(cursor_position(&terminal), (1, 1));
+
+        // lines that no longer fit go to the scrollback, and come back when widening
+        let input = "abcdefghijklmnopqrst\r\n1\r\n2\r\n3\r\n4";
+        let mut terminal = resized(input, 20, 5, &[(10, 5)]);
+        assert_eq!(screen_text(&terminal), ["klmnopqrst", "1", "2", "3", "4"]);
+        assert_eq!(cursor_position(&terminal), (1, 4));
+        terminal.write("\x1b[8;5;20t");
+        assert_eq!(
+            screen_text(&terminal),
+            ["abcdefghijklmnopqrst", "1", "2", "3", "4"]
+        );
+        assert_eq!(cursor_position(&terminal), (1, 4));
+    }
+
+    #[test]
+    fn reflow_hanging_cursor() {
+        // the pending wrap is kept at the end of the line
+        let terminal = resized("abcdefghij", 10, 3, &[(20, 3)]);
+        assert_eq!(screen_text(&terminal), ["abcdefghij", "", ""]);
+        assert_eq!(cursor_position(&terminal), (10, 0));
+
+        let terminal = resized("abcdefghijklmnopqrst", 20, 3, &[(10, 3)]);
+        assert_eq!(screen_text(&terminal), ["abcdefghij", "klmnopqrst", ""]);
+        assert_eq!(cursor_position(&terminal), (10, 1));
+    }
+
+    #[test]
+    fn reflow_height() {
+        let input = "1\r\n2\r\n3\r\n4\r\n5";
+
+        // shrinking pushes lines above the cursor into the scrollback, and growing takes them back
+        let mut terminal = resized(input, 10, 5, &[(10, 3)]);
+        assert_eq!(screen_text(&terminal), ["3", "4", "5"]);
+        assert_eq!(cursor_position(&terminal), (1, 2));
+        terminal.write("\x1b[8;5;10t");
+        assert_eq!(screen_text(&terminal), ["1", "2", "3", "4", "5"]);
+        assert_eq!(cursor_position(&terminal), (1, 4));
+
+        // but only as many as there's room for
+        terminal.write("\x1b[8;3;10t\x1b[8;4;10t");
+        assert_eq!(screen_text(&terminal), ["2", "3", "4", "5"]);
+
+        // content that doesn't reach the bottom stays where it is
+        let terminal = resized("1\r\n2\x1b[1;1H", 10, 3, &[(10, 3), (10, 6)]);
+        assert_eq!(screen_text(&terminal), ["1", "2", "", "", "", ""]);
+        assert_eq!(cursor_position(&terminal), (0, 0));
+        let terminal = resized(input, 10, 5, &[(10, 3), (10, 6)]);
+        assert_eq!(screen_text(&terminal), ["1", "2", "3", "4", "5", ""]);
+    }
+
+    #[test]
+    fn reflow_after_clear() {
+        let input = "line1\r\nline2\r\nline3\r\nline4\r\nline5\r\nline6\r\n";
+
+        // cleared lines don't come back
+        let terminal = resized(&format!("{}\x1b[H\x1b[2J", input), 10, 4, &[(20, 4)]);
+        assert_eq!(screen_text(&terminal), ["", "", "", ""]);
+        assert_eq!(cursor_position(&terminal), (0, 0));
+        let terminal = resized(&format!("{}\x1b[H\x1b[2J", input), 10, 4, &[(10, 6)]);
+        assert_eq!(screen_text(&terminal), ["", "", "", "", "", ""]);
+
+        // and ED 3 clears the scrollback
+        let mut terminal = resized(&format!("{}\x1b[3J", input), 10, 4, &[]);
+        assert!(terminal.state.scrollback.is_empty());
+        assert_eq!(screen_text(&terminal), ["line4", "line5", "line6", ""]);
+        terminal.write("\x1b[8;6;10t");
+        assert_eq!(
+            screen_text(&terminal),
+            ["line4", "line5", "line6", "", "", ""]
+        );
+    }
+
+    #[test]
+    fn alternate_screen_modes() {
+        // 47 keeps the alternate screen's contents and doesn't touch the cursor
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("main\x1b[?47h\x1b[2;1Halt\x1b[?47l");
+        assert_eq!(screen_text(&terminal), ["main", "", ""]);
+        assert_eq!(cursor_position(&terminal), (3, 1));
+        terminal.write("\x1b[?47h");
+        assert_eq!(screen_text(&terminal), ["", "alt", ""]);
+
+        // 1047 clears it when switching back
+        terminal.write("\x1b[?1047l\x1b[?1047h");
+        assert_eq!(screen_text(&terminal), ["", "", ""]);
+        terminal.write("alt\x1b[?1047l\x1b[?47h");
+        assert_eq!(screen_text(&terminal), ["", "", ""]);
+
+        // 1048 saves and restores the cursor without switching screens
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[3;4H\x1b[?1048h\x1b[1;1H\x1b[?1048l");
+        assert_eq!(cursor_position(&terminal), (3, 2));
+
+        // 1049 saves the cursor, switches and clears the alternate screen, and restores both
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[?47halt\x1b[?47l\x1b[Hmain\x1b[3;4H\x1b[?1049h");
+        assert_eq!(screen_text(&terminal), ["", "", ""]);
+        terminal.write("\x1b[1;1Hx\x1b[?1049l");
+        assert_eq!(screen_text(&terminal), ["main", "", ""]);
+        assert_eq!(cursor_position(&terminal), (3, 2));
+    }
+
+    #[test]
+    fn saved_cursor_per_screen() {
+        // saving the cursor on the alternate screen keeps the one 1049 restores
+        let mut terminal = Terminal::new(10, 6);
+        terminal.write("\x1b[3;4H\x1b[?1049h\x1b[5;5H\x1b[s\x1b[?1049l");
+        assert_eq!(cursor_position(&terminal), (3, 2));
+        terminal.write("\x1b[?1049h\x1b[1;1H\x1b7\x1b[?1048h\x1b8");
+        assert_eq!(cursor_position(&terminal), (0, 0));
+
+        // each screen restores its own saved cursor
+        let mut terminal = Terminal::new(10, 6);
+        terminal.write("\x1b[2;2H\x1b[s\x1b[?47h\x1b[4;4H\x1b[s\x1b[1;1H\x1b[u");
+        assert_eq!(cursor_position(&terminal), (3, 3));
+        terminal.write("\x1b[?47l\x1b[u");
+        assert_eq!(cursor_position(&terminal), (1, 1));
+        terminal.write("\x1b[?47h\x1b[u");
+        assert_eq!(cursor_position(&terminal), (3, 3));
+
+        // 1049 replaces the main screen's saved cursor, and 1049 on the alternate screen only
+        // saves into the alternate screen's
+        let mut terminal = Terminal::new(10, 6);
+        terminal.write("\x1b[2;2H\x1b[s\x1b[3;3H\x1b[?1049h\x1b[5;5H\x1b[?1049h\x1b[?1049l");
+        assert_eq!(cursor_position(&terminal), (2, 2));
+        terminal.write("\x1b[1;1H\x1b[u");
+        assert_eq!(cursor_position(&terminal), (2, 2));
+    }
+
+    #[test]
+    fn soft_reset() {
+        let mut terminal = Terminal::new(10, 4);
+        terminal.write("main\x1b[?1049h\x1b[2;3r\x1b[1;4m\x1b(0\x1b[?1h\x1b=\x1b[?25l\x1b[2*x");
+        terminal.write("\x1b[1;1Hq\x1b[!p");
+
+        // the screen contents, the cursor position and the alternate screen are kept
+        assert!(terminal.state.is_alt_buffer);
+        assert_eq!(cursor_position(&terminal), (1, 0));
+        assert_eq!(line_text(&terminal, 0), "\u{2500}         ");
+
+        // modes, margins, character sets and the style are reset
+        assert!(terminal.state.cursor.visible);
+        assert!(!terminal.state.app_cursor_keys);
+        assert!(!terminal.state.app_keypad);
+        assert!(!terminal.state.rect_attr_extent);
+        assert_eq!(
+            (
+                terminal.state.scroll_margin_top,
+                terminal.state.scroll_margin_bottom
+            ),
+            (0, 4)
+        );
+        terminal.write("q");
+        assert_eq!(line_text(&terminal, 0), "\u{2500}q        ");
+        assert_eq!(terminal.state.buffer.lines[0][1].style.attrs, 0);
+
+        terminal.write("\x1b[?1049l");
+        assert_eq!(line_text(&terminal, 0), "main      ");
+    }
+
+    #[test]
+    fn hard_reset() {
+        let mut terminal = Terminal::new(10, 2);
+        terminal.write("\x1b]2;title\x07\x1b[22t\x1b[?1049h\x1b[1mx\x1b[?1049l1\r\n2\r\n3");
+        assert!(!terminal.state.scrollback.is_empty());
+        terminal.write("\x1bc");
+
+        assert!(!terminal.state.is_alt_buffer);
+        assert!(terminal.state.scrollback.is_empty());
+        assert!(terminal.state.title_stack.is_empty());
+        assert_eq!(terminal.state.title, "");
+        assert_eq!(screen_text(&terminal), ["", ""]);
+        assert_eq!(cursor_position(&terminal), (0, 0));
+        terminal.write("\x1b[?1049h");
+        assert_eq!(screen_text(&terminal), ["", ""]);
+
+        // a title pop has nothing to restore
+        terminal.write("\x1b]2;new\x07\x1b[23t");
+        assert_eq!(terminal.state.title, "new");
+    }
+
+    #[test]
+    fn index_and_reverse_index() {
+        let lines = "1\r\n2\r\n3\r\n4\r\n5";
+
+        // IND scrolls the region up at its bottom margin, and only moves down outside of it
+        let mut terminal = Terminal::new(10, 5);
+        terminal.write(lines);
+        terminal.write("\x1b[2;4r\x1b[4;2H\x1bD");
+        assert_eq!(screen_text(&terminal), ["1", "3", "4", "", "5"]);
+        assert_eq!(cursor_position(&terminal), (1, 3));
+        terminal.write("\x1b[5;1H\x1bD\u{84}");
+        assert_eq!(screen_text(&terminal), ["1", "3", "4", "", "5"]);
+        assert_eq!(cursor_position(&terminal), (0, 4));
+
+        // RI scrolls it down at its top margin, and only moves up outside of it
+        terminal.write("\x1b[2;2H\x1bM");
+        assert_eq!(screen_text(&terminal), ["1", "", "3", "4", "5"]);
+        assert_eq!(cursor_position(&terminal), (1, 1));
+        terminal.write("\x1b[1;1H\x1bM\u{8d}");
+        assert_eq!(screen_text(&terminal), ["1", "", "3", "4", "5"]);
+        assert_eq!(cursor_position(&terminal), (0, 0));
+
+        // cursor movement stops at the margins too, but CUP doesn't
+        terminal.write("\x1b[3;1H\x1b[9B");
+        assert_eq!(cursor_position(&terminal), (0, 3));
+        terminal.write("\x1b[9E\x1b[9F");
+        assert_eq!(cursor_position(&terminal), (0, 1));
+        terminal.write("\x1b[5;1H\x1b[A");
+        assert_eq!(cursor_position(&terminal), (0, 3));
+
+        // NEL is a CR and an IND
+        terminal.write("\x1b[3;3H\x1bE");
+        assert_eq!(cursor_position(&terminal), (0, 3));
+        terminal.write("\x1b[4;3H\u{85}");
+        assert_eq!(screen_text(&terminal), ["1", "3", "4", "", "5"]);
+        assert_eq!(cursor_position(&terminal), (0, 3));
+    }
+
+    #[test]
+    fn underline_serialization() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b[4:3;58:2::1:2:3mx\x1b[4:5;58:5:9my\x1b[24;59mz");
+
+        let curly = (1 << 3) | (2 << 12) | (1 << 15);
+        let dashed = (1 << 3) | (4 << 12) | (1 << 15);
+        let mut expected = String::from("S");
+        for &n in &[0, 0, 3, 10] {
+            expected.push(encode_as_code_point(n));
+        }
+        expected.push('\x07');
+        expected += &encode_24color(0x01_02_03 + 256);
+        expected.push('\x04');
+        expected.push(encode_as_code_point(curly));
+        expected.push('x');
+        expected.push('\x07');
+        expected += &encode_24color(9);
+        expected.push('\x04');
+        expected.push(encode_as_code_point(dashed));
+        expected.push('y');
+        // the underline color is kept, though not marked as set
+        expected.push('\x04');
+        expected.push(encode_as_code_point(0));
+        expected.push('z');
+        let screen = terminal.serialize_screen(0.0, true);
+        assert!(screen.starts_with(&expected), "{:?}", screen);
+
+        // DECRQSS reports them the same way
+        terminal.write("\x1b[4:3;58:2::1:2:3m\x1bP$qm\x1b\\");
+        assert_eq!(terminal.take_response(), "\x1bP1$r0;4:3;58;2;1;2;3m\x1b\\");
+        terminal.write("\x1b[0;4;58;5;9m\x1bP$qm\x1b\\");
+        assert_eq!(terminal.take_response(), "\x1bP1$r0;4;58;5;9m\x1b\\");
+    }
+
+    #[test]
+    fn palette_colors() {
+        let mut terminal = Terminal::new(10, 3);
+        // the Linux theme
+        terminal.set_default_colors(1, 7, 0);
+
+        terminal.write("\x1b]4;1;?;2;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]4;1;rgb:aaaa/0000/0000\x1b\\\x1b]4;2;rgb:0000/aaaa/0000\x1b\\"
+        );
+
+        terminal.write("\x1b]4;1;rgb:12/34/56;2;#abc;3;#a1b2c3\x1b\\\x1b]4;1;?;2;?;3;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]4;1;rgb:1212/3434/5656\x1b\\\x1b]4;2;rgb:aaaa/bbbb/cccc\x1b\\\
+             \x1b]4;3;rgb:a1a1/b2b2/c3c3\x1b\\"
+        );
+
+        // resetting restores the theme entries
+        terminal.write("\x1b]104;1\x07\x1b]4;1;?;2;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]4;1;rgb:aaaa/0000/0000\x1b\\\x1b]4;2;rgb:aaaa/bbbb/cccc\x1b\\"
+        );
+        terminal.write("\x1b]104\x07\x1b]4;2;?;3;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]4;2;rgb:0000/aaaa/0000\x1b\\\x1b]4;3;rgb:aaaa/5555/0000\x1b\\"
+        );
+    }
+
+    #[test]
+    fn dynamic_colors() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.set_default_colors(1, 7, 0);
+
+        terminal.write("\x1b]10;?;?;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]10;rgb:aaaa/aaaa/aaaa\x1b\\\x1b]11;rgb:0000/0000/0000\x1b\\\
+             \x1b]12;rgb:aaaa/aaaa/aaaa\x1b\\"
+        );
+
+        terminal.write("\x1b]11;#123;rgb:ff/0/0\x07\x1b]11;?\x07\x1b]12;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]11;rgb:1111/2222/3333\x1b\\\x1b]12;rgb:ffff/0000/0000\x1b\\"
+        );
+
+        // resetting restores the configured colors, which follow the palette
+        terminal.write("\x1b]111\x07\x1b]112\x07\x1b]4;7;#fff\x07\x1b]10;?;?;?\x07");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]10;rgb:ffff/ffff/ffff\x1b\\\x1b]11;rgb:0000/0000/0000\x1b\\\
+             \x1b]12;rgb:ffff/ffff/ffff\x1b\\"
+        );
+        terminal.write("\x1b]10;#123\x07\x1b]110\x07\x1b]10;?\x07");
+        assert_eq!(terminal.take_response(), "\x1b]10;rgb:ffff/ffff/ffff\x1b\\");
+    }
+
+    #[test]
+    fn clipboard_writes() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b]52;c;aGVsbG8=\x07\x1b]52;;d29ybGQ=\x1b\\\x1b]52;c;!!\x07");
+        assert_eq!(terminal.take_clipboard_writes(), ["hello", "world"]);
+        assert!(terminal.take_clipboard_writes().is_empty());
+        assert_eq!(terminal.take_response(), "");
+    }
+
+    #[test]
+    fn clipboard_reads() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.set_clipboard("hello");
+
+        // refused unless allowed
+        terminal.write("\x1b]52;c;?\x07");
+        assert_eq!(terminal.take_response(), "");
+
+        terminal.allow_clipboard_read = true;
+        terminal.write("\x1b]52;c;?\x07\x1b]52;;?\x07\x1b]52;p;?\x1b\\");
+        assert_eq!(
+            terminal.take_response(),
+            "\x1b]52;c;aGVsbG8=\x1b\\\x1b]52;s0;aGVsbG8=\x1b\\\x1b]52;p;aGVsbG8=\x1b\\"
+        );
+        assert!(terminal.take_clipboard_writes().is_empty());
+
+        terminal.set_clipboard("");
+        terminal.write("\x1b]52;c;?\x07");
+        assert_eq!(terminal.take_response(), "\x1b]52;c;\x1b\\");
+    }
+
+    #[test]
+    fn key_modes_in_screen_options() {
+        let mut terminal = Terminal::new(80, 25);
+        terminal.set_default_colors(2, 7, 0);
+        let options = |terminal: &Terminal| -> Vec<u32> {
+            let options = terminal.screen_options();
+            assert!(options.starts_with('O'));
+            // decodes the code points, which are all below 0xD800 here
+            options.chars().skip(1).map(|c| c as u32 - 1).collect()
+        };
+        let base = options(&terminal)[5];
+        assert_eq!(options(&terminal)[..5], [25, 80, 2, 7, 0]);
+        assert_eq!(base & 0b11100, 1 << 4);
+
+        terminal.write("\x1b[?1h");
+        assert_eq!(options(&terminal)[5], base | (1 << 2));
+        terminal.write("\x1b=");
+        assert_eq!(options(&terminal)[5], base | (1 << 2) | (1 << 3));
+        terminal.write("\x1b[?1l");
+        assert_eq!(options(&terminal)[5], base | (1 << 3));
+        terminal.write("\x1b>");
+        assert_eq!(options(&terminal)[5], base);
+
+        // DECNKM sets the keypad mode too
+        terminal.write("\x1b[?66h");
+        assert_eq!(options(&terminal)[5], base | (1 << 3));
+        terminal.write("\x1b[?66l");
+        assert_eq!(options(&terminal)[5], base);
+    }
+
+    /// Writes the input and returns the reply.
+    fn reply(terminal: &mut Terminal, input: &str) -> String {
+        terminal.write(input);
+        terminal.take_response()
+    }
+
+    #[test]
+    fn mode_reports() {
+        let mut terminal = Terminal::new(10, 3);
+        assert_eq!(reply(&mut terminal, "\x1b[?25$p"), "\x1b[?25;1$y");
+        assert_eq!(reply(&mut terminal, "\x1b[?1$p"), "\x1b[?1;2$y");
+        assert_eq!(reply(&mut terminal, "\x1b[?1h\x1b[?1$p"), "\x1b[?1;1$y");
+        assert_eq!(reply(&mut terminal, "\x1b[?12$p"), "\x1b[?12;1$y");
+        assert_eq!(reply(&mut terminal, "\x1b[2 q\x1b[?12$p"), "\x1b[?12;2$y");
+        assert_eq!(
+            reply(&mut terminal, "\x1b[?1049h\x1b[?1049$p\x1b[?47$p"),
+            "\x1b[?1049;1$y\x1b[?47;1$y"
+        );
+
+        // unknown modes and ANSI modes
+        assert_eq!(reply(&mut terminal, "\x1b[?9999$p"), "\x1b[?9999;0$y");
+        assert_eq!(reply(&mut terminal, "\x1b[4$p"), "\x1b[4;0$y");
+        // not DECRQM
+        assert_eq!(reply(&mut terminal, "\x1b[>1$p\x1b[?$p"), "");
+    }
+
+    #[test]
+    fn setting_reports() {
+        let mut terminal = Terminal::new(10, 3);
+        assert_eq!(reply(&mut terminal, "\x1bP$qm\x1b\\"), "\x1bP1$r0m\x1b\\");
+        assert_eq!(
+            reply(&mut terminal, "\x1b[1;31;48;5;100m\x1bP$qm\x1b\\"),
+            "\x1bP1$r0;1;31;48;5;100m\x1b\\"
+        );
+        assert_eq!(reply(&mut terminal, "\x1bP$qr\x1b\\"), "\x1bP1$r1;3r\x1b\\");
+        assert_eq!(
+            reply(&mut terminal, "\x1b[2;3r\x1bP$qr\x1b\\"),
+            "\x1bP1$r2;3r\x1b\\"
+        );
+        assert_eq!(reply(&mut terminal, "\x1bP$q q\x1b\\"), "\x1bP1$r1 q\x1b\\");
+        assert_eq!(
+            reply(&mut terminal, "\x1b[1\"q\x1bP$q\"q\x1b\\"),
+            "\x1bP1$r1\"q\x1b\\"
+        );
+        assert_eq!(
+            reply(&mut terminal, "\x1bP$qs\x1b\\"),
+            "\x1bP1$r1;10s\x1b\\"
+        );
+
+        // invalid requests
+        assert_eq!(reply(&mut terminal, "\x1bP$qx\x1b\\"), "\x1bP0$r\x1b\\");
+        assert_eq!(reply(&mut terminal, "\x1bP$q\x1b\\"), "\x1bP0$r\x1b\\");
+    }
+
+    #[test]
+    fn termcap_reports() {
+        let mut terminal = Terminal::new(10, 3);
+        assert_eq!(
+            reply(&mut terminal, "\x1bP+q544E\x1b\\"),
+            "\x1bP1+r544E=787465726D2D323536636F6C6F72\x1b\\"
+        );
+        assert_eq!(
+            reply(&mut terminal, "\x1bP+q436F;524742\x1b\\"),
+            "\x1bP1+r436F=323536\x1b\\\x1bP1+r524742=382F382F38\x1b\\"
+        );
+
+        // unknown names are reported as such, and names that aren't hex are skipped
+        assert_eq!(
+            reply(&mut terminal, "\x1bP+q666F6F\x1b\\"),
+            "\x1bP0+r666F6F\x1b\\"
+        );
+        assert_eq!(
+            reply(&mut terminal, "\x1bP+qTN;436;436f\x1b\\"),
+            "\x1bP1+r436F=323536\x1b\\"
+        );
+    }
+
+    #[test]
+    fn application_program_commands_are_ignored() {
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("a\x1b_Gf=100;AAAA\x1b\\b\x1bPq#0;2;0;0;0\x1b\\c\x1b^pm\x1b\\d");
+        assert_eq!(screen_text(&terminal), ["abcd", "", ""]);
+        assert_eq!(cursor_position(&terminal), (4, 0));
+        assert_eq!(terminal.take_response(), "");
+    }
+
+    #[test]
+    fn single_and_locking_shifts() {
+        // G2 and G3 as DEC special graphics and the UK set
+        let mut terminal = Terminal::new(10, 3);
+        terminal.write("\x1b*0\x1b+A");
+        assert_eq!(
+            (terminal.get_code_page(2), terminal.get_code_page(3)),
+            ('0', 'A')
+        );
+
+        // single shifts apply to the next character only, even across controls
+        terminal.write("\x1bNqq\x1bO##\x1bN\rq");
+        assert_eq!(line_text(&terminal, 0), "\u{2500}q£#      ");
+        assert_eq!(terminal.current_code_page(), 0);
+
+        // locking shifts last until another one
+        terminal.write("\r\n\x1bnqq\x1bO#q");
+        assert_eq!(terminal.current_code_page(), 2);
+        assert_eq!(line_text(&terminal, 1), "\u{2500}\u{2500}£\u{2500}      ");
+        terminal.write("\r\n\x1bo##\x0f#\x0e");
+        assert_eq!(line_text(&terminal, 2), "££#       ");
+        assert_eq!(terminal.current_code_page(), 1);
+    }
+
+    #[test]
+    fn cursor_styles() {
+        let shape = |terminal: &Terminal| (terminal.attributes() >> 9) & 0b111;
+        let mut terminal = Terminal::new(10, 3);
+        assert_eq!(shape(&terminal), 1);
+
+        for style in 1..=6 {
+            terminal.write(&format!("\x1b[{} q", style));
+            assert_eq!(shape(&terminal), style);
+            assert_eq!(terminal.state.cursor.is_blinking(), style % 2 == 1);
+        }
+
+        // 0 is the default style, and unknown styles are ignored
+        terminal.write("\x1b[0 q");
+        assert_eq!(shape(&terminal), 1);
+        terminal.set_default_cursor_style(4);
+        assert_eq!(shape(&terminal), 4);
+        terminal.write("\x1b[2 q\x1b[ q");
+        assert_eq!(shape(&terminal), 4);
+        terminal.write("\x1b[7 q\x1b[q");
+        assert_eq!(shape(&terminal), 4);
+
+        // cursor blink mode switches between the blinking and steady variants
+        for &(style, steady, blinking) in &[(1, 2, 1), (2, 2, 1), (3, 4, 3), (6, 6, 5)] {
+            terminal.write(&format!("\x1b[{} q\x1b[?12l", style));
+            assert_eq!(shape(&terminal), steady);
+            terminal.write("\x1b[?12h");
+            assert_eq!(shape(&terminal), blinking);
+        }
+    }
+}
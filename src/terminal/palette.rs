@@ -0,0 +1,201 @@
+// Theme colors as defined in the ESPTerm front end (themes.js)
+
+/// The first 16 colors of each theme, in theme order.
+const THEMES: &[[u32; 16]] = &[
+    // Tango
+    [
+        0x111213, 0xCC0000, 0x4E9A06, 0xC4A000, 0x3465A4, 0x75507B, 0x06989A, 0xD3D7CF, 0x555753,
+        0xEF2929, 0x8AE234, 0xFCE94F, 0x729FCF, 0xAD7FA8, 0x34E2E2, 0xEEEEEC,
+    ],
+    // Linux
+    [
+        0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA, 0x555555,
+        0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF,
+    ],
+    // XTerm
+    [
+        0x000000, 0xCD0000, 0x00CD00, 0xCDCD00, 0x0000EE, 0xCD00CD, 0x00CDCD, 0xE5E5E5, 0x7F7F7F,
+        0xFF0000, 0x00FF00, 0xFFFF00, 0x5C5CFF, 0xFF00FF, 0x00FFFF, 0xFFFFFF,
+    ],
+    // Rxvt
+    [
+        0x000000, 0xCD0000, 0x00CD00, 0xCDCD00, 0x0000CD, 0xCD00CD, 0x00CDCD, 0xFAEBD7, 0x404040,
+        0xFF0000, 0x00FF00, 0xFFFF00, 0x0000FF, 0xFF00FF, 0x00FFFF, 0xFFFFFF,
+    ],
+    // Ambience
+    [
+        0x2E3436, 0xCC0000, 0x4E9A06, 0xC4A000, 0x3465A4, 0x75507B, 0x06989A, 0xD3D7CF, 0x555753,
+        0xEF2929, 0x8AE234, 0xFCE94F, 0x729FCF, 0xAD7FA8, 0x34E2E2, 0xEEEEEC,
+    ],
+    // Solarized
+    [
+        0x073642, 0xDC322F, 0x859900, 0xB58900, 0x268BD2, 0xD33682, 0x2AA198, 0xEEE8D5, 0x002B36,
+        0xCB4B16, 0x586E75, 0x657B83, 0x839496, 0x6C71C4, 0x93A1A1, 0xFDF6E3,
+    ],
+];
+
+/// Returns the RGB value of a color in the xterm 256-color palette for the given theme.
+fn theme_color(theme: usize, index: usize) -> u32 {
+    match index {
+        0..=15 => THEMES.get(theme).unwrap_or(&THEMES[0])[index],
+        16..=231 => {
+            // 6×6×6 color cube
+            let level = |x: usize| if x == 0 { 0 } else { (x * 40 + 55) as u32 };
+            let i = index - 16;
+            (level(i / 36) << 16) | (level(i / 6 % 6) << 8) | level(i % 6)
+        }
+        _ => {
+            // grayscale ramp
+            let gray = ((index - 232) * 10 + 8) as u32;
+            (gray << 16) | (gray << 8) | gray
+        }
+    }
+}
+
+/// Colors that can be changed using OSC 10 through 12.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicColor {
+    Foreground,
+    Background,
+    Cursor,
+}
+
+/// A terminal color palette.
+///
+/// Palette entries are RGB values. The dynamic colors use the same encoding as cell colors, i.e.
+/// either a palette index below 256 or an RGB value offset by 256.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: Vec<u32>,
+    pub fg: u32,
+    pub bg: u32,
+    pub cursor: u32,
+}
+
+impl Palette {
+    pub fn new(theme: u32, fg: u32, bg: u32) -> Palette {
+        Palette {
+            colors: (0..256).map(|i| theme_color(theme as usize, i)).collect(),
+            fg,
+            bg,
+            cursor: fg,
+        }
+    }
+
+    /// Resolves a color in cell color encoding to an RGB value.
+    pub fn resolve(&self, color: u32) -> u32 {
+        if color < 256 {
+            self.colors[color as usize]
+        } else {
+            color - 256
+        }
+    }
+
+    pub fn dynamic(&self, which: DynamicColor) -> u32 {
+        match which {
+            DynamicColor::Foreground => self.fg,
+            DynamicColor::Background => self.bg,
+            DynamicColor::Cursor => self.cursor,
+        }
+    }
+
+    pub fn dynamic_mut(&mut self, which: DynamicColor) -> &mut u32 {
+        match which {
+            DynamicColor::Foreground => &mut self.fg,
+            DynamicColor::Background => &mut self.bg,
+            DynamicColor::Cursor => &mut self.cursor,
+        }
+    }
+}
+
+/// Parses an X11 color specification as used in OSC 4 and OSC 10 through 12.
+///
+/// Supports `rgb:r/g/b` with 1–4 hex digits per component and `#rgb` with 1–4 hex digits per
+/// component. Returns an RGB value.
+pub fn parse_color_spec(spec: &str) -> Option<u32> {
+    // scales a component with n hex digits to 8 bits
+    fn component(hex: &str) -> Option<u32> {
+        if hex.is_empty() || hex.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        let max = (1 << (hex.len() * 4)) - 1;
+        Some((value * 255 + max / 2) / max)
+    }
+
+    let components: Vec<&str> = if spec.starts_with("rgb:") {
+        spec[4..].split('/').collect()
+    } else if spec.starts_with('#') && spec.len() > 1 && (spec.len() - 1) % 3 == 0 {
+        let n = (spec.len() - 1) / 3;
+        if !spec.is_char_boundary(1 + n) || !spec.is_char_boundary(1 + 2 * n) {
+            return None;
+        }
+        vec![&spec[1..1 + n], &spec[1 + n..1 + 2 * n], &spec[1 + 2 * n..]]
+    } else {
+        return None;
+    };
+
+    if components.len() != 3 {
+        return None;
+    }
+
+    let r = component(components[0])?;
+    let g = component(components[1])?;
+    let b = component(components[2])?;
+    Some((r << 16) | (g << 8) | b)
+}
+
+/// Formats an RGB value the way xterm reports colors.
+pub fn format_color_spec(rgb: u32) -> String {
+    let r = (rgb >> 16) & 0xFF;
+    let g = (rgb >> 8) & 0xFF;
+    let b = rgb & 0xFF;
+    format!("rgb:{:04x}/{:04x}/{:04x}", r * 0x101, g * 0x101, b * 0x101)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_specs() {
+        let cases = [
+            ("rgb:12/34/56", 0x12_34_56),
+            ("rgb:1/2/3", 0x11_22_33),
+            ("rgb:fff/800/000", 0xFF_80_00),
+            ("rgb:FFFF/8080/0000", 0xFF_80_00),
+            ("#abc", 0xAA_BB_CC),
+            ("#a1b2c3", 0xA1_B2_C3),
+            ("#a12b34c56", 0xA1_B3_C5),
+            ("#a123b456c789", 0xA1_B4_C7),
+        ];
+        for &(spec, rgb) in &cases {
+            assert_eq!(parse_color_spec(spec), Some(rgb), "{:?}", spec);
+        }
+
+        for spec in &[
+            "",
+            "rgb:",
+            "rgb:1/2",
+            "rgb:1/2/3/4",
+            "rgb:12345/0/0",
+            "rgb:x/0/0",
+            "#12",
+        ] {
+            assert_eq!(parse_color_spec(spec), None, "{:?}", spec);
+        }
+        assert_eq!(parse_color_spec("#ab"), None);
+        assert_eq!(parse_color_spec("#éab"), None);
+        assert_eq!(parse_color_spec("red"), None);
+    }
+
+    #[test]
+    fn color_spec_round_trip() {
+        assert_eq!(format_color_spec(0x12_34_56), "rgb:1212/3434/5656");
+        for &rgb in &[0, 0xFF_FF_FF, 0x12_34_56, 0xA1_00_FE] {
+            assert_eq!(parse_color_spec(&format_color_spec(rgb)), Some(rgb));
+            let short = format!("#{:06x}", rgb);
+            assert_eq!(parse_color_spec(&short), Some(rgb));
+        }
+    }
+}
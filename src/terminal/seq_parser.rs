@@ -1,19 +1,57 @@
+use super::diagnostics::UnhandledKind;
+use super::palette::{parse_color_spec, DynamicColor};
+use base64;
 use std::mem;
 
+/// Parser states, following the DEC VT500 state machine described by Paul Williams.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum SequenceType {
-    None,
-    ESC,
-    ANSI,
-    OSC,
-    OneChar,
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
 }
 
+/// Maximum number of parameters in a CSI or DCS sequence; sequences with more are ignored.
+const MAX_PARAMS: usize = 32;
+
+/// Maximum number of colon-separated sub-parameters per parameter; sequences with more are
+/// ignored.
+const MAX_SUB_PARAMS: usize = 8;
+
+/// Maximum value of a numeric parameter; larger values are clamped.
+const MAX_PARAM_VALUE: i32 = 65535;
+
+/// Maximum number of intermediate bytes; sequences with more are ignored.
+const MAX_INTERMEDIATES: usize = 2;
+
+/// Maximum length of an OSC string, which leaves room for a few tens of kilobytes of OSC 52
+/// clipboard data; longer ones are discarded.
+const MAX_OSC_LENGTH: usize = 65536;
+
+/// Maximum width and height the screen can be resized to using XTWINOPS.
+const MAX_SCREEN_SIZE: i32 = 1000;
+
+/// Maximum length of a collected DCS string; longer ones (e.g. sixel images) are discarded.
+const MAX_DCS_LENGTH: usize = 4096;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ClearType {
     Before,
     After,
     All,
+    /// Only used by ED, to clear the scrollback.
+    Scrollback,
 }
 
 impl From<i32> for ClearType {
@@ -21,6 +59,7 @@ impl From<i32> for ClearType {
         match num {
             1 => ClearType::Before,
             2 => ClearType::All,
+            3 => ClearType::Scrollback,
             _ => ClearType::After,
         }
     }
@@ -39,21 +78,169 @@ impl Default for LineSize {
         LineSize::Normal
     }
 }
+
+/// Alternate screen buffer mode variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AltBufferMode {
+    /// Mode 47: plain buffer swap.
+    Swap,
+    /// Mode 1047: buffer swap, clearing the alternate screen when leaving it.
+    ClearOnExit,
+    /// Mode 1049: saves the cursor, swaps buffers and clears the alternate screen.
+    SaveCursor,
+}
+
+/// Which of the window title and icon name an operation applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleTarget {
+    Both,
+    IconName,
+    Title,
+}
+
+impl From<i32> for TitleTarget {
+    fn from(num: i32) -> TitleTarget {
+        match num {
+            1 => TitleTarget::IconName,
+            2 => TitleTarget::Title,
+            _ => TitleTarget::Both,
+        }
+    }
+}
+
+/// Window properties that can be queried using XTWINOPS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowReport {
+    State,
+    Position,
+    TextAreaPixels,
+    TextAreaChars,
+    ScreenChars,
+}
+
+/// A rectangular screen area, as used by the VT420 rectangular area operations.
+///
+/// Coordinates are 0-based and the bottom and right edges are exclusive, so they may extend past
+/// the screen to mean "up to the last line or column".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub top: u32,
+    pub left: u32,
+    pub bottom: u32,
+    pub right: u32,
+}
+
+impl Rect {
+    /// Reads a rectangle from four parameters (top, left, bottom, right) starting at the given
+    /// index. Missing or zero bottom and right parameters mean the last line and column.
+    fn from_params(params: &[Option<i32>], index: usize) -> Rect {
+        let end = |param: Option<i32>| match param {
+            Some(x) if x > 0 => x as u32,
+            _ => u32::max_value(),
+        };
+        Rect {
+            top: position(params.get_opt(index)),
+            left: position(params.get_opt(index + 1)),
+            bottom: end(params.get_opt(index + 2)),
+            right: end(params.get_opt(index + 3)),
+        }
+    }
+}
+
+/// Attributes that can be changed in rectangular areas: bold, underline, blink and inverse, with
+/// the SGR parameters that set and clear them.
+const RECT_ATTRS: [(i32, i32, u32); 4] = [
+    (1, 22, 1 << 2),
+    (4, 24, 1 << 3),
+    (5, 25, 1 << 5),
+    (7, 27, 1 << 4),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnderlineStyle {
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CodePage {
     DECSpecialChars,
     DOS437,
+    Blocks,
+    LinesExtra,
     UK,
     USASCII,
+    Dutch,
+    Finnish,
+    French,
+    FrenchCanadian,
+    German,
+    Italian,
+    NorwegianDanish,
+    Spanish,
+    Swedish,
+    Swiss,
 }
 
 impl CodePage {
+    /// Returns the code page with the given designator, as used in ESC ( and friends.
+    pub fn from_designator(c: char) -> Option<CodePage> {
+        Some(match c {
+            '0' => CodePage::DECSpecialChars,
+            '1' => CodePage::DOS437,
+            '2' => CodePage::Blocks,
+            '3' => CodePage::LinesExtra,
+            'A' => CodePage::UK,
+            'B' => CodePage::USASCII,
+            '4' => CodePage::Dutch,
+            'C' | '5' => CodePage::Finnish,
+            'R' | 'f' => CodePage::French,
+            'Q' | '9' => CodePage::FrenchCanadian,
+            'K' => CodePage::German,
+            'Y' => CodePage::Italian,
+            'E' | '6' | '`' => CodePage::NorwegianDanish,
+            'Z' => CodePage::Spanish,
+            'H' | '7' => CodePage::Swedish,
+            '=' => CodePage::Swiss,
+            _ => return None,
+        })
+    }
+
     pub fn as_char(self) -> char {
         match self {
             CodePage::DECSpecialChars => '0',
             CodePage::DOS437 => '1',
+            CodePage::Blocks => '2',
+            CodePage::LinesExtra => '3',
             CodePage::UK => 'A',
             CodePage::USASCII => 'B',
+            CodePage::Dutch => '4',
+            CodePage::Finnish => 'C',
+            CodePage::French => 'R',
+            CodePage::FrenchCanadian => 'Q',
+            CodePage::German => 'K',
+            CodePage::Italian => 'Y',
+            CodePage::NorwegianDanish => 'E',
+            CodePage::Spanish => 'Z',
+            CodePage::Swedish => 'H',
+            CodePage::Swiss => '=',
+        }
+    }
+
+    /// Whether this is a national replacement character set, which can only be designated while
+    /// DECNRCM is set.
+    pub fn is_national(self) -> bool {
+        match self {
+            CodePage::DECSpecialChars
+            | CodePage::DOS437
+            | CodePage::Blocks
+            | CodePage::LinesExtra
+            | CodePage::UK
+            | CodePage::USASCII => false,
+            _ => true,
         }
     }
 }
@@ -65,9 +252,10 @@ pub enum Action {
     SetCursorLine(u32),
     MoveCursor(i32, i32),
     MoveCursorLine(i32),
-    MoveCursorLineWithScroll(i32),
-    ClearScreen(ClearType),
-    ClearLine(ClearType),
+    Index,
+    ReverseIndex,
+    ClearScreen(ClearType, bool),
+    ClearLine(ClearType, bool),
     InsertLines(u32),
     DeleteLines(u32),
     DeleteForward(u32),
@@ -75,26 +263,57 @@ pub enum Action {
     Scroll(i32),
     InsertBlanks(u32),
     SetCursorStyle(u8),
+    SetCursorBlink(bool),
     SaveCursor,
     RestoreCursor,
     SetCursorVisible(bool),
-    SetAltBuffer(bool),
+    SetAltBuffer(bool, AltBufferMode),
     SetScrollMargin(u32, u32),
     ResetStyle,
-    AddAttrs(u16),
-    RemoveAttrs(u16),
+    AddAttrs(u32),
+    RemoveAttrs(u32),
+    SetUnderline(Option<UnderlineStyle>),
     SetColorFG(u32),
     SetColorBG(u32),
+    SetColorUnderline(u32),
     ResetColorFG,
     ResetColorBG,
+    ResetColorUnderline,
     SetReverseVideo(bool),
     SetBracketedPaste(bool),
+    SetAppCursorKeys(bool),
+    SetAppKeypad(bool),
+    SetFocusEvents(bool),
     SetWindowTitle(String),
+    SetIconName(String),
+    PushTitle(TitleTarget),
+    PopTitle(TitleTarget),
+    ReportTitle(TitleTarget),
+    ReportWindow(WindowReport),
     SetRainbowMode(bool),
+    SetHyperlink(String),
+    SetClipboard(String),
+    QueryClipboard(String),
+    SetPaletteColor(u8, u32),
+    QueryPaletteColor(u8),
+    ResetPaletteColor(Option<u8>),
+    SetDynamicColor(DynamicColor, u32),
+    QueryDynamicColor(DynamicColor),
+    ResetDynamicColor(DynamicColor),
     SetMouseTracking(bool),
     SetLineSize(LineSize),
+    ScreenAlignmentTest,
     SetCodePage(u8, CodePage),
     SetCharSet(u8),
+    FillRect(char, Rect),
+    EraseRect(Rect, bool),
+    CopyRect(Rect, u32, u32),
+    ChangeRectAttrs(Rect, u32, u32),
+    ReverseRectAttrs(Rect, u32),
+    SetRectAttrExtent(bool),
+    SetProtected(bool),
+    SetNationalCharsets(bool),
+    SingleShift(u8),
     Interrupt,
     Bell,
     Backspace,
@@ -104,16 +323,38 @@ pub enum Action {
     DeleteLine,
     DeleteWord,
     Write(String),
+    RepeatChar(u32),
     Resize(u32, u32),
+    Reset(bool),
+    QueryMode(bool, u32),
+    QuerySetting(String),
+    QueryTermcap(String),
+    Unhandled(UnhandledKind, String),
 }
 
 /// Escape sequence parser.
 pub struct SeqParser {
-    /// Current sequence type.
-    seq_type: SequenceType,
+    /// Current parser state.
+    state: State,
+
+    /// Private marker of the current CSI or DCS sequence (one of `<`, `=`, `>` and `?`).
+    private: Option<char>,
+
+    /// Intermediate bytes of the current sequence.
+    intermediates: String,
+
+    /// Parameters of the current CSI or DCS sequence, each with its colon-separated
+    /// sub-parameters.
+    params: Vec<Vec<Option<i32>>>,
 
-    /// Current sequence.
-    sequence: String,
+    /// Final character of the current DCS sequence.
+    dcs_final: char,
+
+    /// Contents of the current OSC or DCS string.
+    data: String,
+
+    /// Whether the current string got too long and will be discarded.
+    overflow: bool,
 
     /// Accumulated actions.
     actions: Vec<Action>,
@@ -123,17 +364,90 @@ pub struct SeqParser {
 trait OptionalVec<T> {
     fn get_opt(&self, index: usize) -> Option<T>;
 }
-impl OptionalVec<i32> for Vec<Option<i32>> {
+impl OptionalVec<i32> for [Option<i32>] {
     fn get_opt(&self, index: usize) -> Option<i32> {
         *self.get(index).unwrap_or(&None)
     }
 }
 
+/// Reads the color following SGR 38, 48 or 58, either from colon-separated sub-parameters
+/// (`38:5:n`, `38:2::r:g:b` or `38:2:r:g:b`) or from the following parameters (`38;5;n`,
+/// `38;2;r;g;b`).
+fn read_sgr_color<I>(group: &[Option<i32>], params: &mut I) -> Option<u32>
+where
+    I: Iterator<Item = Vec<Option<i32>>>,
+{
+    let component = |x: Option<i32>| x.unwrap_or(0).max(0).min(255) as u32;
+
+    let args: Vec<Option<i32>> = if group.len() > 1 {
+        let args = &group[1..];
+        if args.get_opt(0) == Some(2) && args.len() > 4 {
+            // skip the color space ID
+            let mut rgb = vec![args[0]];
+            rgb.extend_from_slice(&args[2..5]);
+            rgb
+        } else {
+            args.to_vec()
+        }
+    } else {
+        match params.next()?.get_opt(0) {
+            Some(5) => vec![Some(5), params.next()?.get_opt(0)],
+            Some(2) => vec![
+                Some(2),
+                params.next()?.get_opt(0),
+                params.next()?.get_opt(0),
+                params.next()?.get_opt(0),
+            ],
+            _ => return None,
+        }
+    };
+
+    match args.get_opt(0) {
+        Some(5) if args.len() > 1 => Some(component(args[1])),
+        Some(2) if args.len() > 3 => {
+            let (r, g, b) = (component(args[1]), component(args[2]), component(args[3]));
+            Some((r << 16) + (g << 8) + b + 256)
+        }
+        _ => None,
+    }
+}
+
+/// Converts a 1-based position parameter to a 0-based one; a missing or zero parameter means the
+/// first position.
+fn position(param: Option<i32>) -> u32 {
+    (param.unwrap_or(1).max(1) - 1) as u32
+}
+
+/// Returns the attribute parameters of DECCARA and DECRARA, which follow the rectangle.
+fn rect_attr_params(params: &[Option<i32>]) -> Vec<i32> {
+    if params.len() > 4 {
+        params[4..].iter().map(|x| x.unwrap_or(0)).collect()
+    } else {
+        vec![0]
+    }
+}
+
+/// Decodes a hex encoded string, as used in XTGETTCAP.
+fn decode_hex(data: &str) -> Option<String> {
+    if data.len() % 2 != 0 || !data.is_ascii() {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..data.len() / 2)
+        .map(|i| u8::from_str_radix(&data[i * 2..i * 2 + 2], 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
+}
+
 impl SeqParser {
     pub fn new() -> SeqParser {
         SeqParser {
-            seq_type: SequenceType::None,
-            sequence: String::new(),
+            state: State::Ground,
+            private: None,
+            intermediates: String::new(),
+            params: Vec::new(),
+            dcs_final: '\0',
+            data: String::new(),
+            overflow: false,
             actions: Vec::new(),
         }
     }
@@ -142,415 +456,1066 @@ impl SeqParser {
         mem::replace(&mut self.actions, Vec::new())
     }
 
-    fn reset_state(&mut self) {
-        self.seq_type = SequenceType::None;
-        self.sequence = String::new();
+    /// Clears the collected sequence and enters the given state.
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.private = None;
+        self.intermediates.clear();
+        self.params.clear();
+        self.dcs_final = '\0';
+        self.data.clear();
+        self.overflow = false;
     }
 
-    pub fn apply_seq(&mut self) {
-        let seq = mem::replace(&mut self.sequence, String::new());
-        self.reset_state();
-        let first_char = seq.chars().next().unwrap_or(0 as char);
+    fn unhandled(&mut self, kind: UnhandledKind, sequence: String) {
+        self.actions.push(Action::Unhandled(kind, sequence));
+    }
 
-        match first_char {
-            '[' => {
-                // ANSI
-                let chars: Vec<_> = seq.chars().collect();
+    fn unhandled_escape(&mut self, action: char) {
+        let sequence = format!("\x1b{}{}", self.intermediates, action);
+        self.unhandled(UnhandledKind::Escape, sequence);
+    }
 
-                // ANSI action is the last character (like m in \e[0m)
-                let action = *chars.last().unwrap();
+    fn unhandled_csi(&mut self, action: char) {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|group| {
+                let sub: Vec<String> = group
+                    .iter()
+                    .map(|x| x.map(|x| x.to_string()).unwrap_or_default())
+                    .collect();
+                sub.join(":")
+            })
+            .collect();
+        let sequence = format!(
+            "\x1b[{}{}{}{}",
+            self.private.map(|c| c.to_string()).unwrap_or_default(),
+            params.join(";"),
+            self.intermediates,
+            action
+        );
+        self.unhandled(UnhandledKind::Csi, sequence);
+    }
 
-                // content between [ and action
-                let content = if chars.len() > 2 {
-                    (chars[1..chars.len() - 1]).iter().collect()
-                } else {
-                    String::new()
+    /// Returns the first sub-parameter of the given parameter.
+    fn param(&self, index: usize) -> Option<i32> {
+        self.params.get(index).and_then(|group| group.get_opt(0))
+    }
+
+    /// Collects a parameter byte (a digit, `;` or `:`). Returns false if the parameter limits
+    /// were exceeded.
+    fn collect_param(&mut self, c: char) -> bool {
+        if self.params.is_empty() {
+            self.params.push(vec![None]);
+        }
+        match c {
+            ';' => {
+                if self.params.len() >= MAX_PARAMS {
+                    return false;
+                }
+                self.params.push(vec![None]);
+            }
+            ':' => {
+                let group = self.params.last_mut().unwrap();
+                if group.len() >= MAX_SUB_PARAMS {
+                    return false;
+                }
+                group.push(None);
+            }
+            _ => {
+                let digit = c.to_digit(10).unwrap_or(0) as i32;
+                let value = self.params.last_mut().unwrap().last_mut().unwrap();
+                *value = Some((value.unwrap_or(0) * 10 + digit).min(MAX_PARAM_VALUE));
+            }
+        }
+        true
+    }
+
+    /// Collects an intermediate byte. Returns false if there are too many.
+    fn collect_intermediate(&mut self, c: char) -> bool {
+        if self.intermediates.len() >= MAX_INTERMEDIATES {
+            return false;
+        }
+        self.intermediates.push(c);
+        true
+    }
+
+    /// Appends a character to the OSC or DCS string, discarding it if it gets too long.
+    fn put(&mut self, c: char) {
+        let max_length = if self.state == State::OscString {
+            MAX_OSC_LENGTH
+        } else {
+            MAX_DCS_LENGTH
+        };
+        if self.data.len() >= max_length {
+            self.overflow = true;
+        } else if !self.overflow {
+            self.data.push(c);
+        }
+    }
+
+    /// Writes a printable character, merging it with a preceding write.
+    fn print(&mut self, c: char) {
+        if let Some(Action::Write(ref mut text)) = self.actions.last_mut() {
+            text.push(c);
+            return;
+        }
+        self.actions.push(Action::Write(c.to_string()));
+    }
+
+    /// Executes a C0 control character.
+    fn execute(&mut self, c: char) {
+        match c as u32 {
+            3 => self.actions.push(Action::Interrupt),
+            7 => self.actions.push(Action::Bell),
+            8 => self.actions.push(Action::Backspace),
+            9 => self.actions.push(Action::Tab),
+            0xA..=0xC => self.actions.push(Action::NewLine),
+            0xD => self.actions.push(Action::Return),
+            0xE => self.actions.push(Action::SetCharSet(1)),
+            0xF => self.actions.push(Action::SetCharSet(0)),
+            0x15 => self.actions.push(Action::DeleteLine),
+            0x17 => self.actions.push(Action::DeleteWord),
+            _ => (),
+        }
+    }
+
+    /// Handles a C1 control character, which may appear anywhere.
+    fn execute_c1(&mut self, c: char) {
+        match c as u32 {
+            0x84 => {
+                self.enter(State::Ground);
+                self.actions.push(Action::Index);
+            }
+            0x85 => {
+                self.enter(State::Ground);
+                self.actions.push(Action::Return);
+                self.actions.push(Action::Index);
+            }
+            0x8D => {
+                self.enter(State::Ground);
+                self.actions.push(Action::ReverseIndex);
+            }
+            0x8E | 0x8F => {
+                self.enter(State::Ground);
+                self.actions.push(Action::SingleShift(c as u8 - 0x8C));
+            }
+            0x90 => self.enter(State::DcsEntry),
+            0x98 | 0x9E | 0x9F => self.enter(State::SosPmApcString),
+            0x9B => self.enter(State::CsiEntry),
+            0x9D => self.enter(State::OscString),
+            _ => self.enter(State::Ground),
+        }
+    }
+
+    /// Leaves a string state, dispatching the collected OSC or DCS string.
+    fn end_string(&mut self) {
+        match self.state {
+            State::OscString if !self.overflow => {
+                let data = mem::replace(&mut self.data, String::new());
+                self.osc_dispatch(&data);
+            }
+            State::DcsPassthrough if !self.overflow => {
+                let data = mem::replace(&mut self.data, String::new());
+                self.dcs_dispatch(&data);
+            }
+            _ => (),
+        }
+        self.enter(State::Ground);
+    }
+
+    fn apply_sgr(&mut self, params: Vec<Vec<Option<i32>>>) {
+        // SGR parameters may have colon-separated sub-parameters, like 4:3 or 38:2::r:g:b
+        if params.is_empty() {
+            self.actions.push(Action::ResetStyle);
+            return;
+        }
+
+        let mut params = params.into_iter();
+        while let Some(group) = params.next() {
+            let sgr_type = group.get_opt(0).unwrap_or(0);
+            match sgr_type {
+                // reset
+                0 => self.actions.push(Action::ResetStyle),
+                // bold
+                1 => self.actions.push(Action::AddAttrs(1 << 2)),
+                // faint
+                2 => self.actions.push(Action::AddAttrs(1 << 9)),
+                // italic
+                3 => self.actions.push(Action::AddAttrs(1 << 6)),
+                // underline, with an optional style sub-parameter
+                4 => {
+                    let style = match group.get_opt(1).unwrap_or(1) {
+                        0 => None,
+                        2 => Some(UnderlineStyle::Double),
+                        3 => Some(UnderlineStyle::Curly),
+                        4 => Some(UnderlineStyle::Dotted),
+                        5 => Some(UnderlineStyle::Dashed),
+                        _ => Some(UnderlineStyle::Single),
+                    };
+                    self.actions.push(Action::SetUnderline(style));
+                }
+                // blink
+                5 | 6 => self.actions.push(Action::AddAttrs(1 << 5)),
+                // invert
+                7 => self.actions.push(Action::AddAttrs(1 << 4)),
+                // conceal
+                8 => self.actions.push(Action::AddAttrs(1 << 11)),
+                // strike
+                9 => self.actions.push(Action::AddAttrs(1 << 7)),
+                // fraktur
+                20 => self.actions.push(Action::AddAttrs(1 << 10)),
+                // double underline
+                21 => self
+                    .actions
+                    .push(Action::SetUnderline(Some(UnderlineStyle::Double))),
+                // remove bold and faint
+                22 => self.actions.push(Action::RemoveAttrs((1 << 2) | (1 << 9))),
+                // remove italic and fraktur
+                23 => self.actions.push(Action::RemoveAttrs((1 << 6) | (1 << 10))),
+                // remove underline
+                24 => self.actions.push(Action::SetUnderline(None)),
+                // remove blink
+                25 => self.actions.push(Action::RemoveAttrs(1 << 5)),
+                // remove inverse
+                27 => self.actions.push(Action::RemoveAttrs(1 << 4)),
+                // remove conceal
+                28 => self.actions.push(Action::RemoveAttrs(1 << 11)),
+                // remove strike
+                29 => self.actions.push(Action::RemoveAttrs(1 << 7)),
+                // set foreground
+                color @ 30..=37 => self.actions.push(Action::SetColorFG(color as u32 % 10)),
+                // set background
+                color @ 40..=47 => self.actions.push(Action::SetColorBG(color as u32 % 10)),
+                // reset foreground
+                39 => self.actions.push(Action::ResetColorFG),
+                // reset background
+                49 => self.actions.push(Action::ResetColorBG),
+                // overline
+                53 => self.actions.push(Action::AddAttrs(1 << 8)),
+                // remove overline
+                55 => self.actions.push(Action::RemoveAttrs(1 << 8)),
+                // reset underline color
+                59 => self.actions.push(Action::ResetColorUnderline),
+                // set bright foreground
+                color @ 90..=97 => self
+                    .actions
+                    .push(Action::SetColorFG((color as u32 % 10) + 8)),
+                // set bright background
+                color @ 100..=107 => self
+                    .actions
+                    .push(Action::SetColorBG((color as u32 % 10) + 8)),
+                38 | 48 | 58 => match read_sgr_color(&group, &mut params) {
+                    Some(color) => self.actions.push(match sgr_type {
+                        38 => Action::SetColorFG(color),
+                        48 => Action::SetColorBG(color),
+                        _ => Action::SetColorUnderline(color),
+                    }),
+                    // the remaining parameters can't be interpreted reliably
+                    None if group.len() == 1 => break,
+                    None => (),
+                },
+                _ => {
+                    let group: Vec<String> = group
+                        .iter()
+                        .map(|x| x.map(|x| x.to_string()).unwrap_or_default())
+                        .collect();
+                    let sequence = format!("\x1b[{}m", group.join(":"));
+                    self.unhandled(UnhandledKind::Sgr, sequence);
+                }
+            }
+        }
+    }
+
+    fn esc_dispatch(&mut self, action: char) {
+        match &*self.intermediates {
+            "" => match action {
+                '[' => return self.enter(State::CsiEntry),
+                ']' => return self.enter(State::OscString),
+                'P' => return self.enter(State::DcsEntry),
+                'X' | '^' | '_' => return self.enter(State::SosPmApcString),
+                '=' => self.actions.push(Action::SetAppKeypad(true)),
+                '>' => self.actions.push(Action::SetAppKeypad(false)),
+                'D' => self.actions.push(Action::Index),
+                'E' => {
+                    self.actions.push(Action::Return);
+                    self.actions.push(Action::Index);
+                }
+                'M' => self.actions.push(Action::ReverseIndex),
+                'N' => self.actions.push(Action::SingleShift(2)),
+                'O' => self.actions.push(Action::SingleShift(3)),
+                'n' => self.actions.push(Action::SetCharSet(2)),
+                'o' => self.actions.push(Action::SetCharSet(3)),
+                'c' => self.actions.push(Action::Reset(true)),
+                // ST
+                '\\' => (),
+                _ => self.unhandled(UnhandledKind::Escape, format!("\x1b{}", action)),
+            },
+            "(" | ")" | "*" | "+" => {
+                let g = match &*self.intermediates {
+                    "(" => 0,
+                    ")" => 1,
+                    "*" => 2,
+                    _ => 3,
                 };
+                match CodePage::from_designator(action) {
+                    Some(page) => self.actions.push(Action::SetCodePage(g, page)),
+                    None => self.unhandled_escape(action),
+                }
+            }
+            "#" => match action {
+                '3' => self
+                    .actions
+                    .push(Action::SetLineSize(LineSize::DoubleHeightTop)),
+                '4' => self
+                    .actions
+                    .push(Action::SetLineSize(LineSize::DoubleHeightBottom)),
+                '5' => self.actions.push(Action::SetLineSize(LineSize::Normal)),
+                '6' => self
+                    .actions
+                    .push(Action::SetLineSize(LineSize::DoubleWidth)),
+                '8' => self.actions.push(Action::ScreenAlignmentTest),
+                _ => self.unhandled_escape(action),
+            },
+            _ => self.unhandled_escape(action),
+        }
+        self.enter(State::Ground);
+    }
 
-                // often, ANSI uses numbers separated by semicolons so here's a vec for that
-                let numbers: Vec<Option<i32>> = if content.trim().len() == 0 {
-                    Vec::new()
+    /// Applies DECSET/DECRST (with the `?` private marker) or SM/RM for each parameter.
+    fn set_modes(&mut self, enable: bool) {
+        let modes: Vec<i32> = (0..self.params.len())
+            .filter_map(|i| self.param(i))
+            .collect();
+        for mode in modes {
+            match (self.private, mode) {
+                (Some('?'), 1) => self.actions.push(Action::SetAppCursorKeys(enable)),
+                (Some('?'), 5) => self.actions.push(Action::SetReverseVideo(enable)),
+                (Some('?'), 12) => self.actions.push(Action::SetCursorBlink(enable)),
+                (Some('?'), 25) => self.actions.push(Action::SetCursorVisible(enable)),
+                (Some('?'), 42) => self.actions.push(Action::SetNationalCharsets(enable)),
+                (Some('?'), 66) => self.actions.push(Action::SetAppKeypad(enable)),
+                (Some('?'), 1000) => self.actions.push(Action::SetMouseTracking(enable)),
+                (Some('?'), 1004) => self.actions.push(Action::SetFocusEvents(enable)),
+                (Some('?'), 47) => self
+                    .actions
+                    .push(Action::SetAltBuffer(enable, AltBufferMode::Swap)),
+                (Some('?'), 1047) => self
+                    .actions
+                    .push(Action::SetAltBuffer(enable, AltBufferMode::ClearOnExit)),
+                (Some('?'), 1048) => self.actions.push(if enable {
+                    Action::SaveCursor
                 } else {
-                    content
-                        .trim()
-                        .split(';')
-                        .map(|x| match x.parse::<i32>() {
-                            Ok(x) => Some(x),
-                            Err(_) => None,
-                        })
-                        .collect()
-                };
+                    Action::RestoreCursor
+                }),
+                (Some('?'), 1049) => self
+                    .actions
+                    .push(Action::SetAltBuffer(enable, AltBufferMode::SaveCursor)),
+                (Some('?'), 2004) => self.actions.push(Action::SetBracketedPaste(enable)),
+                // TODO
+                _ => {
+                    let sequence = format!(
+                        "\x1b[{}{}{}",
+                        self.private.map(|c| c.to_string()).unwrap_or_default(),
+                        mode,
+                        if enable { 'h' } else { 'l' }
+                    );
+                    self.unhandled(UnhandledKind::Mode, sequence);
+                }
+            }
+        }
+    }
 
-                match action {
-                    'H' | 'f' => {
-                        let y = numbers.get_opt(0).map(|x| x - 1).unwrap_or(0);
-                        let x = numbers.get_opt(1).map(|x| x - 1).unwrap_or(0);
-                        self.actions.push(Action::SetCursor(x as u32, y as u32));
-                    }
-                    'A' => self
-                        .actions
-                        .push(Action::MoveCursor(0, -numbers.get_opt(0).unwrap_or(1))),
-                    'B' => self
-                        .actions
-                        .push(Action::MoveCursor(0, numbers.get_opt(0).unwrap_or(1))),
-                    'C' => self
-                        .actions
-                        .push(Action::MoveCursor(numbers.get_opt(0).unwrap_or(1), 0)),
-                    'D' => self
-                        .actions
-                        .push(Action::MoveCursor(-numbers.get_opt(0).unwrap_or(1), 0)),
-                    'E' => self
-                        .actions
-                        .push(Action::MoveCursorLine(numbers.get_opt(0).unwrap_or(1))),
-                    'F' => self
-                        .actions
-                        .push(Action::MoveCursorLine(-numbers.get_opt(0).unwrap_or(1))),
-                    'G' => self.actions.push(Action::SetCursorX(
-                        (numbers.get_opt(0).unwrap_or(1) as u32) - 1,
-                    )),
-                    'J' => {
-                        let clear_type: ClearType = numbers.get_opt(0).unwrap_or(0).into();
-                        self.actions.push(Action::ClearScreen(clear_type));
-                    }
-                    'K' => {
-                        let clear_type: ClearType = numbers.get_opt(0).unwrap_or(0).into();
-                        self.actions.push(Action::ClearLine(clear_type));
-                    }
-                    'L' => self
-                        .actions
-                        .push(Action::InsertLines(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    'M' => self
-                        .actions
-                        .push(Action::DeleteLines(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    'P' => self
-                        .actions
-                        .push(Action::DeleteForward(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    'S' => self.actions.push(Action::Scroll(1)),
-                    'T' => self.actions.push(Action::Scroll(-1)),
-                    'X' => self
-                        .actions
-                        .push(Action::EraseForward(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    '@' => self
-                        .actions
-                        .push(Action::InsertBlanks(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    'd' => self
-                        .actions
-                        .push(Action::SetCursorLine(numbers.get_opt(0).unwrap_or(1) as u32)),
-                    'q' => self
-                        .actions
-                        .push(Action::SetCursorStyle(numbers.get_opt(0).unwrap_or(1) as u8)),
-                    'r' => {
-                        let top = numbers.get_opt(0).map(|x| x - 1).unwrap_or(0);
-                        let bottom = numbers.get_opt(1).map(|x| x - 1).unwrap_or(0);
+    fn csi_dispatch(&mut self, action: char) {
+        // only the first sub-parameter of each parameter is used, except in SGR
+        let numbers: Vec<Option<i32>> = (0..self.params.len()).map(|i| self.param(i)).collect();
+        let plain = self.private.is_none() && self.intermediates.is_empty();
+
+        match action {
+            'H' | 'f' if plain => {
+                let y = position(numbers.get_opt(0));
+                let x = position(numbers.get_opt(1));
+                self.actions.push(Action::SetCursor(x, y));
+            }
+            'A' if plain => self
+                .actions
+                .push(Action::MoveCursor(0, -numbers.get_opt(0).unwrap_or(1))),
+            'B' if plain => self
+                .actions
+                .push(Action::MoveCursor(0, numbers.get_opt(0).unwrap_or(1))),
+            'C' if plain => self
+                .actions
+                .push(Action::MoveCursor(numbers.get_opt(0).unwrap_or(1), 0)),
+            'D' if plain => self
+                .actions
+                .push(Action::MoveCursor(-numbers.get_opt(0).unwrap_or(1), 0)),
+            'E' if plain => self
+                .actions
+                .push(Action::MoveCursorLine(numbers.get_opt(0).unwrap_or(1))),
+            'F' if plain => self
+                .actions
+                .push(Action::MoveCursorLine(-numbers.get_opt(0).unwrap_or(1))),
+            'G' if plain => self
+                .actions
+                .push(Action::SetCursorX(position(numbers.get_opt(0)))),
+            'J' if plain || (self.private == Some('?') && self.intermediates.is_empty()) => {
+                let clear_type: ClearType = numbers.get_opt(0).unwrap_or(0).into();
+                let selective = !plain;
+                self.actions
+                    .push(Action::ClearScreen(clear_type, selective));
+            }
+            'K' if plain || (self.private == Some('?') && self.intermediates.is_empty()) => {
+                let clear_type: ClearType = numbers.get_opt(0).unwrap_or(0).into();
+                let selective = !plain;
+                self.actions.push(Action::ClearLine(clear_type, selective));
+            }
+            'L' if plain => self
+                .actions
+                .push(Action::InsertLines(numbers.get_opt(0).unwrap_or(1) as u32)),
+            'M' if plain => self
+                .actions
+                .push(Action::DeleteLines(numbers.get_opt(0).unwrap_or(1) as u32)),
+            'P' if plain => self
+                .actions
+                .push(Action::DeleteForward(numbers.get_opt(0).unwrap_or(1) as u32)),
+            'S' if plain => self
+                .actions
+                .push(Action::Scroll(numbers.get_opt(0).unwrap_or(1).max(1))),
+            'T' if plain => self
+                .actions
+                .push(Action::Scroll(-numbers.get_opt(0).unwrap_or(1).max(1))),
+            'X' if plain => self
+                .actions
+                .push(Action::EraseForward(numbers.get_opt(0).unwrap_or(1) as u32)),
+            '@' if plain => self
+                .actions
+                .push(Action::InsertBlanks(numbers.get_opt(0).unwrap_or(1) as u32)),
+            'b' if plain => self.actions.push(Action::RepeatChar(
+                numbers.get_opt(0).unwrap_or(1).max(1) as u32,
+            )),
+            'd' if plain => self
+                .actions
+                .push(Action::SetCursorLine(position(numbers.get_opt(0)))),
+            'q' if self.private.is_none() && self.intermediates == " " => {
+                // DECSCUSR, 0 selects the default style
+                match numbers.get_opt(0).unwrap_or(0) {
+                    style @ 0..=6 => self.actions.push(Action::SetCursorStyle(style as u8)),
+                    _ => self.unhandled_csi(action),
+                }
+            }
+            'r' if plain => {
+                let top = position(numbers.get_opt(0));
+                let bottom = position(numbers.get_opt(1));
+                self.actions.push(Action::SetScrollMargin(top, bottom));
+            }
+            's' if plain => self.actions.push(Action::SaveCursor),
+            'u' if plain => self.actions.push(Action::RestoreCursor),
+            'm' if plain => {
+                let params = mem::replace(&mut self.params, Vec::new());
+                self.apply_sgr(params);
+            }
+            'h' | 'l' if self.intermediates.is_empty() => self.set_modes(action == 'h'),
+            'p' if self.private.is_none() && self.intermediates == "!" => {
+                self.actions.push(Action::Reset(false))
+            }
+            'p' if self.private != Some('<')
+                && self.private != Some('=')
+                && self.private != Some('>')
+                && self.intermediates == "$" =>
+            {
+                // DECRQM
+                if let Some(mode) = numbers.get_opt(0) {
+                    self.actions
+                        .push(Action::QueryMode(self.private.is_some(), mode as u32));
+                }
+            }
+            'x' if self.private.is_none() && self.intermediates == "$" => {
+                // DECFRA
+                let c = numbers.get_opt(0).unwrap_or(0);
+                match c {
+                    32..=126 | 160..=255 => {
+                        let rect = Rect::from_params(&numbers, 1);
                         self.actions
-                            .push(Action::SetScrollMargin(top as u32, bottom as u32));
+                            .push(Action::FillRect(char::from(c as u8), rect));
                     }
-                    's' => self.actions.push(Action::SaveCursor),
-                    'u' => self.actions.push(Action::RestoreCursor),
-                    'm' => {
-                        if numbers.len() == 0 {
-                            self.actions.push(Action::ResetStyle);
-                        } else {
-                            let mut numbers = numbers.into_iter();
-                            loop {
-                                if let Some(sgr_type) = numbers.next() {
-                                    let sgr_type = sgr_type.unwrap_or(0);
-                                    match sgr_type {
-                                        // reset
-                                        0 => self.actions.push(Action::ResetStyle),
-                                        // bold
-                                        1 => self.actions.push(Action::AddAttrs(1 << 2)),
-                                        // faint
-                                        2 => self.actions.push(Action::AddAttrs(1 << 9)),
-                                        // italic
-                                        3 => self.actions.push(Action::AddAttrs(1 << 6)),
-                                        // underline
-                                        4 => self.actions.push(Action::AddAttrs(1 << 3)),
-                                        // blink
-                                        5 | 6 => self.actions.push(Action::AddAttrs(1 << 5)),
-                                        // invert
-                                        7 => self.actions.push(Action::AddAttrs(1 << 4)),
-                                        // strike
-                                        9 => self.actions.push(Action::AddAttrs(1 << 7)),
-                                        // fraktur
-                                        20 => self.actions.push(Action::AddAttrs(1 << 10)),
-                                        // remove bold
-                                        21 => self.actions.push(Action::RemoveAttrs(1 << 2)),
-                                        // remove bold and faint
-                                        22 => self
-                                            .actions
-                                            .push(Action::RemoveAttrs((1 << 2) | (1 << 9))),
-                                        // remove italic and fraktur
-                                        23 => self
-                                            .actions
-                                            .push(Action::RemoveAttrs((1 << 6) | (1 << 10))),
-                                        // remove underline
-                                        24 => self.actions.push(Action::RemoveAttrs(1 << 3)),
-                                        // remove blink
-                                        25 => self.actions.push(Action::RemoveAttrs(1 << 5)),
-                                        // remove inverse
-                                        27 => self.actions.push(Action::RemoveAttrs(1 << 4)),
-                                        // set foreground
-                                        color @ 30...37 => {
-                                            self.actions.push(Action::SetColorFG(color as u32 % 10))
-                                        }
-                                        // set background
-                                        color @ 40...47 => {
-                                            self.actions.push(Action::SetColorBG(color as u32 % 10))
-                                        }
-                                        // reset foreground
-                                        39 => self.actions.push(Action::ResetColorFG),
-                                        // reset background
-                                        49 => self.actions.push(Action::ResetColorBG),
-                                        // set bright foreground
-                                        color @ 90...97 => self
-                                            .actions
-                                            .push(Action::SetColorFG((color as u32 % 10) + 8)),
-                                        // set bright background
-                                        color @ 100...107 => self
-                                            .actions
-                                            .push(Action::SetColorBG((color as u32 % 10) + 8)),
-                                        38 | 48 => {
-                                            let is_fg = sgr_type == 38;
-                                            if let Some(type_mod) = numbers.next() {
-                                                let type_mod = type_mod.unwrap_or(0);
-                                                match type_mod {
-                                                    2 => {
-                                                        if let Some(r) = numbers.next() {
-                                                            if let Some(g) = numbers.next() {
-                                                                if let Some(b) = numbers.next() {
-                                                                    let r = r.unwrap_or(0);
-                                                                    let g = g.unwrap_or(0);
-                                                                    let b = b.unwrap_or(0);
-                                                                    let color: u32 = ((r << 16) + (g << 8) + b + 256) as u32;
-                                                                    if is_fg {
-                                                                        self.actions.push(
-                                                                            Action::SetColorFG(
-                                                                                color,
-                                                                            ),
-                                                                        );
-                                                                    } else {
-                                                                        self.actions.push(
-                                                                            Action::SetColorBG(
-                                                                                color,
-                                                                            ),
-                                                                        );
-                                                                    }
-                                                                } else {
-                                                                    break;
-                                                                }
-                                                            } else {
-                                                                break;
-                                                            }
-                                                        } else {
-                                                            break;
-                                                        }
-                                                    }
-                                                    5 => {
-                                                        if let Some(color) = numbers.next() {
-                                                            let color = color.unwrap_or(0);
-                                                            if is_fg {
-                                                                self.actions.push(
-                                                                    Action::SetColorFG(
-                                                                        color as u32,
-                                                                    ),
-                                                                );
-                                                            } else {
-                                                                self.actions.push(
-                                                                    Action::SetColorBG(
-                                                                        color as u32,
-                                                                    ),
-                                                                );
-                                                            }
-                                                        } else {
-                                                            break;
-                                                        }
-                                                    }
-                                                    _ => (),
-                                                }
-                                            } else {
-                                                break;
-                                            }
-                                        }
-                                        _ => {
-                                            println!("Unhandled SGR: {}", seq);
-                                        }
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
+                    _ => (),
+                }
+            }
+            'z' | '{' if self.private.is_none() && self.intermediates == "$" => {
+                // DECERA and DECSERA
+                let rect = Rect::from_params(&numbers, 0);
+                self.actions.push(Action::EraseRect(rect, action == '{'));
+            }
+            'v' if self.private.is_none() && self.intermediates == "$" => {
+                // DECCRA; pages are ignored
+                let rect = Rect::from_params(&numbers, 0);
+                let top = position(numbers.get_opt(5));
+                let left = position(numbers.get_opt(6));
+                self.actions.push(Action::CopyRect(rect, top, left));
+            }
+            'r' if self.private.is_none() && self.intermediates == "$" => {
+                // DECCARA
+                let rect = Rect::from_params(&numbers, 0);
+                let (mut set, mut clear) = (0, 0);
+                for param in rect_attr_params(&numbers) {
+                    for &(on, off, attr) in &RECT_ATTRS {
+                        if param == on {
+                            set |= attr;
+                            clear &= !attr;
+                        } else if param == 0 || param == off {
+                            clear |= attr;
+                            set &= !attr;
                         }
                     }
-                    'h' | 'l' => {
-                        match &*content {
-                            "?5" => self.actions.push(Action::SetReverseVideo(action == 'h')),
-                            "?25" => self.actions.push(Action::SetCursorVisible(action == 'h')),
-                            "?1000" => self.actions.push(Action::SetMouseTracking(action == 'h')),
-                            "?1049" => {
-                                // TODO: proper behavior
-                                self.actions.push(Action::SetAltBuffer(action == 'h'));
-                            }
-                            "?2004" => {
-                                self.actions.push(Action::SetBracketedPaste(action == 'h'));
-                            }
-                            // TODO
-                            _ => {
-                                println!("Unhandled mode: {}", seq);
-                            }
-                        };
+                }
+                self.actions.push(Action::ChangeRectAttrs(rect, set, clear));
+            }
+            't' if self.private.is_none() && self.intermediates == "$" => {
+                // DECRARA
+                let rect = Rect::from_params(&numbers, 0);
+                let mut attrs = 0;
+                for param in rect_attr_params(&numbers) {
+                    for &(on, _, attr) in &RECT_ATTRS {
+                        if param == 0 || param == on {
+                            attrs |= attr;
+                        }
+                    }
+                }
+                self.actions.push(Action::ReverseRectAttrs(rect, attrs));
+            }
+            'q' if self.private.is_none() && self.intermediates == "\"" => {
+                // DECSCA
+                let protected = numbers.get_opt(0) == Some(1);
+                self.actions.push(Action::SetProtected(protected));
+            }
+            'x' if self.private.is_none() && self.intermediates == "*" => {
+                // DECSACE
+                let rectangle = numbers.get_opt(0) == Some(2);
+                self.actions.push(Action::SetRectAttrExtent(rectangle));
+            }
+            't' if plain => match numbers.get_opt(0).unwrap_or(0) {
+                8 => {
+                    let height = numbers.get_opt(1).unwrap_or(24).max(1).min(MAX_SCREEN_SIZE);
+                    let width = numbers
+                        .get_opt(2)
+                        .unwrap_or(80)
+                        .max(10)
+                        .min(MAX_SCREEN_SIZE);
+                    self.actions
+                        .push(Action::Resize(width as u32, height as u32));
+                }
+                11 => self.actions.push(Action::ReportWindow(WindowReport::State)),
+                13 => self
+                    .actions
+                    .push(Action::ReportWindow(WindowReport::Position)),
+                14 => self
+                    .actions
+                    .push(Action::ReportWindow(WindowReport::TextAreaPixels)),
+                18 => self
+                    .actions
+                    .push(Action::ReportWindow(WindowReport::TextAreaChars)),
+                19 => self
+                    .actions
+                    .push(Action::ReportWindow(WindowReport::ScreenChars)),
+                20 => self
+                    .actions
+                    .push(Action::ReportTitle(TitleTarget::IconName)),
+                21 => self.actions.push(Action::ReportTitle(TitleTarget::Title)),
+                22 => {
+                    let target = numbers.get_opt(1).unwrap_or(0).into();
+                    self.actions.push(Action::PushTitle(target));
+                }
+                23 => {
+                    let target = numbers.get_opt(1).unwrap_or(0).into();
+                    self.actions.push(Action::PopTitle(target));
+                }
+                _ => self.unhandled_csi(action),
+            },
+            _ => self.unhandled_csi(action),
+        }
+        self.enter(State::Ground);
+    }
+
+    /// Dispatches a DCS string once it has been terminated.
+    fn dcs_dispatch(&mut self, data: &str) {
+        let final_char = self.dcs_final;
+        match (self.private, &*self.intermediates, final_char) {
+            (None, "$", 'q') => {
+                // DECRQSS
+                self.actions.push(Action::QuerySetting(String::from(data)));
+            }
+            (None, "+", 'q') => {
+                // XTGETTCAP, with hex encoded capability names
+                for name in data.split(';') {
+                    if let Some(name) = decode_hex(name) {
+                        self.actions.push(Action::QueryTermcap(name));
                     }
-                    't' => match numbers.get_opt(0).unwrap_or(0) {
-                        8 => {
-                            let height = numbers.get_opt(1).unwrap_or(24).max(1).min(65535);
-                            let width = numbers.get_opt(2).unwrap_or(80).max(10).min(65535);
+                }
+            }
+            // sixel graphics are not supported
+            (None, "", 'q') => (),
+            _ => {
+                let sequence = format!(
+                    "\x1bP{}{}{}",
+                    self.private.map(|c| c.to_string()).unwrap_or_default(),
+                    self.intermediates,
+                    final_char
+                );
+                self.unhandled(UnhandledKind::Dcs, sequence);
+            }
+        }
+    }
+
+    /// Dispatches an OSC string once it has been terminated.
+    fn osc_dispatch(&mut self, osc: &str) {
+        let mut data = osc.split(';');
+        if let Some(osc_type) = data.next() {
+            match osc_type {
+                "0" | "1" | "2" => {
+                    // icon name and/or window title, which may contain semicolons
+                    if let Some(title) = osc.splitn(2, ';').nth(1) {
+                        if osc_type != "2" {
+                            self.actions.push(Action::SetIconName(String::from(title)));
+                        }
+                        if osc_type != "1" {
                             self.actions
-                                .push(Action::Resize(width as u32, height as u32));
+                                .push(Action::SetWindowTitle(String::from(title)));
                         }
-                        _ => println!("Unhandled ANSI sequence: {}", seq),
-                    },
-                    _ => {
-                        println!("Unhandled ANSI sequence: {}", seq);
                     }
                 }
-            }
-            ']' => {
-                // OSC
-                let mut data = seq[1..].split(';');
-                if let Some(osc_type) = data.next() {
-                    match osc_type {
-                        "0" => {
-                            // window title
-                            if let Some(title) = data.next() {
-                                self.actions
-                                    .push(Action::SetWindowTitle(String::from(title)));
+                "360" => {
+                    // rainbow mode
+                    let mut enabled = false;
+                    if let Some(arg) = data.next() {
+                        if arg == "1" {
+                            enabled = true;
+                        }
+                    }
+                    self.actions.push(Action::SetRainbowMode(enabled));
+                }
+                "8" => {
+                    // hyperlink; the URI may contain semicolons, parameters are ignored
+                    let mut parts = osc.splitn(3, ';').skip(2);
+                    let uri = parts.next().unwrap_or("");
+                    self.actions.push(Action::SetHyperlink(String::from(uri)));
+                }
+                "52" => {
+                    // clipboard access, with base64 encoded data
+                    let selection = data.next().unwrap_or("");
+                    let selection = if selection.is_empty() {
+                        "s0"
+                    } else {
+                        selection
+                    };
+                    match data.next() {
+                        Some("?") => self
+                            .actions
+                            .push(Action::QueryClipboard(String::from(selection))),
+                        Some(text) => {
+                            if let Ok(text) = base64::decode(text) {
+                                let text = String::from_utf8_lossy(&text).into_owned();
+                                self.actions.push(Action::SetClipboard(text));
                             }
                         }
-                        "360" => {
-                            // rainbow mode
-                            let mut enabled = false;
-                            if let Some(arg) = data.next() {
-                                if arg == "1" {
-                                    enabled = true;
-                                }
+                        None => (),
+                    }
+                }
+                "4" => {
+                    // palette colors, as index;spec pairs
+                    while let (Some(index), Some(spec)) = (data.next(), data.next()) {
+                        if let Ok(index) = index.parse::<u8>() {
+                            if spec == "?" {
+                                self.actions.push(Action::QueryPaletteColor(index));
+                            } else if let Some(color) = parse_color_spec(spec) {
+                                self.actions.push(Action::SetPaletteColor(index, color));
                             }
-                            self.actions.push(Action::SetRainbowMode(enabled));
                         }
-                        _ => (),
                     }
                 }
+                "10" | "11" | "12" => {
+                    // dynamic colors; additional arguments apply to the following ones
+                    let dynamic_colors = [
+                        DynamicColor::Foreground,
+                        DynamicColor::Background,
+                        DynamicColor::Cursor,
+                    ];
+                    let first = match osc_type {
+                        "10" => 0,
+                        "11" => 1,
+                        _ => 2,
+                    };
+                    for (&which, spec) in dynamic_colors[first..].iter().zip(data) {
+                        if spec == "?" {
+                            self.actions.push(Action::QueryDynamicColor(which));
+                        } else if let Some(color) = parse_color_spec(spec) {
+                            self.actions.push(Action::SetDynamicColor(which, color));
+                        }
+                    }
+                }
+                "104" => {
+                    // reset palette colors, or all of them if there are no arguments
+                    let mut indices = data.filter_map(|x| x.parse::<u8>().ok()).peekable();
+                    if indices.peek().is_none() {
+                        self.actions.push(Action::ResetPaletteColor(None));
+                    }
+                    for index in indices {
+                        self.actions.push(Action::ResetPaletteColor(Some(index)));
+                    }
+                }
+                "110" => self
+                    .actions
+                    .push(Action::ResetDynamicColor(DynamicColor::Foreground)),
+                "111" => self
+                    .actions
+                    .push(Action::ResetDynamicColor(DynamicColor::Background)),
+                "112" => self
+                    .actions
+                    .push(Action::ResetDynamicColor(DynamicColor::Cursor)),
+                _ => self.unhandled(UnhandledKind::Osc, format!("\x1b]{}", osc_type)),
             }
-            '(' | ')' => {
-                let mut chars = seq.chars();
-                let g: u8 = match chars.next().unwrap_or('(') {
-                    '(' => 0,
-                    ')' => 1,
-                    _ => 0,
-                };
-
-                match chars.next() {
-                    Some('0') => self
-                        .actions
-                        .push(Action::SetCodePage(g, CodePage::DECSpecialChars)),
-                    Some('1') => self.actions.push(Action::SetCodePage(g, CodePage::DOS437)),
-                    Some('A') => self.actions.push(Action::SetCodePage(g, CodePage::UK)),
-                    Some('B') => self.actions.push(Action::SetCodePage(g, CodePage::USASCII)),
-                    _ => println!("Unhandled code page: {}", seq),
-                }
-            }
-            'D' => self.actions.push(Action::MoveCursorLineWithScroll(1)),
-            'M' => self.actions.push(Action::MoveCursorLineWithScroll(-1)),
-            '\u{f}' => self.actions.push(Action::SetCharSet(0)),
-            '\u{e}' => self.actions.push(Action::SetCharSet(1)),
-            '#' => {
-                let mut chars = seq.chars();
-                chars.next(); // skip #
-                match chars.next() {
-                    Some('3') => self
-                        .actions
-                        .push(Action::SetLineSize(LineSize::DoubleHeightTop)),
-                    Some('4') => self
-                        .actions
-                        .push(Action::SetLineSize(LineSize::DoubleHeightBottom)),
-                    Some('5') => self.actions.push(Action::SetLineSize(LineSize::Normal)),
-                    Some('6') => self
-                        .actions
-                        .push(Action::SetLineSize(LineSize::DoubleWidth)),
-                    _ => println!("Unhandled #: {}", seq),
-                }
-            }
-            'c' => {
-                self.drain_actions();
-                self.reset_state();
-                self.actions.push(Action::SetCursor(0, 0));
-                self.actions.push(Action::ResetStyle);
-                self.actions.push(Action::ClearScreen(ClearType::All));
-            }
-            _ => {
-                println!("Unhandled escape: {}", seq);
-            }
-        };
+        }
     }
 
     pub fn write(&mut self, data: &str) {
         for c in data.chars() {
-            let code_point = c as u32;
-
-            if code_point == 0x1b && self.seq_type == SequenceType::None {
-                self.seq_type = SequenceType::ESC;
-            } else if code_point == 0x9d && self.seq_type == SequenceType::None {
-                self.seq_type = SequenceType::OSC;
-                self.sequence = String::from("]");
-            } else if c == '[' && self.seq_type == SequenceType::ESC {
-                self.seq_type = SequenceType::ANSI;
-                self.sequence = String::from("[");
-            } else if c == ']' && self.seq_type == SequenceType::ESC {
-                self.seq_type = SequenceType::OSC;
-                self.sequence = String::from("]");
-            } else if (c == '(' || c == ')' || c == '#') && self.seq_type == SequenceType::ESC {
-                self.seq_type = SequenceType::OneChar;
-                self.sequence.push(c);
-            } else if self.seq_type != SequenceType::None
-                && self.seq_type != SequenceType::ESC
-                && (code_point == 0x1b || code_point == 0x9c || code_point == 0x07)
-            {
-                self.apply_seq();
-                if code_point == 0x1b {
-                    self.seq_type = SequenceType::ESC;
-                }
-            } else if self.seq_type == SequenceType::ANSI && '\x40' <= c && c <= '\x7e' {
-                self.sequence.push(c);
-                self.apply_seq();
-            } else if self.seq_type == SequenceType::ESC {
-                if c == '\\' {
-                    // ST
-                    self.reset_state()
-                } else {
-                    self.sequence.push(c);
-                    self.apply_seq();
-                }
-            } else if self.seq_type != SequenceType::None {
-                self.sequence.push(c);
-                if self.seq_type == SequenceType::OneChar {
-                    self.apply_seq();
-                }
-            } else {
-                match code_point {
-                    0...2 => (),
-                    3 => self.actions.push(Action::Interrupt),
-                    4...6 => (),
-                    7 => self.actions.push(Action::Bell),
-                    8 => self.actions.push(Action::Backspace),
-                    9 => self.actions.push(Action::Tab),
-                    0xA => self.actions.push(Action::NewLine),
-                    0xD => self.actions.push(Action::Return),
-                    0x15 => self.actions.push(Action::DeleteLine),
-                    0x17 => self.actions.push(Action::DeleteWord),
-                    _ => {
-                        self.actions.push(Action::Write(c.to_string()));
+            self.advance(c);
+        }
+    }
+
+    fn advance(&mut self, c: char) {
+        let code_point = c as u32;
+        let in_string = match self.state {
+            State::OscString | State::DcsPassthrough | State::DcsIgnore => true,
+            State::SosPmApcString => true,
+            _ => false,
+        };
+
+        // transitions that apply in any state
+        match code_point {
+            // CAN and SUB abort the current sequence
+            0x18 | 0x1A => return self.enter(State::Ground),
+            0x1B => {
+                if in_string {
+                    self.end_string();
+                }
+                return self.enter(State::Escape);
+            }
+            // ST
+            0x9C => return self.end_string(),
+            0x80..=0x9F => {
+                if in_string {
+                    self.end_string();
+                }
+                return self.execute_c1(c);
+            }
+            _ => (),
+        }
+
+        let is_c0 = code_point < 0x20;
+        let is_param = c.is_ascii_digit() || c == ';' || c == ':';
+        let is_private = '<' <= c && c <= '?';
+        let is_intermediate = ' ' <= c && c <= '/';
+        let is_final = '@' <= c && c <= '~';
+
+        match self.state {
+            State::Ground => {
+                if is_c0 {
+                    self.execute(c);
+                } else if code_point != 0x7F {
+                    self.print(c);
+                }
+            }
+            State::Escape | State::EscapeIntermediate => {
+                if is_c0 {
+                    self.execute(c);
+                } else if is_intermediate {
+                    self.collect_intermediate(c);
+                    self.state = State::EscapeIntermediate;
+                } else if '0' <= c && c <= '~' {
+                    self.esc_dispatch(c);
+                } else if code_point > 0x7F {
+                    // can't be part of the sequence, so it's printed instead
+                    self.enter(State::Ground);
+                    self.print(c);
+                }
+            }
+            State::CsiEntry | State::CsiParam | State::CsiIntermediate => {
+                let entry = self.state == State::CsiEntry;
+                if is_c0 {
+                    self.execute(c);
+                } else if is_final {
+                    self.csi_dispatch(c);
+                } else if is_intermediate {
+                    self.state = if self.collect_intermediate(c) {
+                        State::CsiIntermediate
+                    } else {
+                        State::CsiIgnore
+                    };
+                } else if self.state == State::CsiIntermediate {
+                    // parameters may not follow intermediates
+                    if code_point != 0x7F {
+                        self.state = State::CsiIgnore;
                     }
+                } else if is_private && entry {
+                    self.private = Some(c);
+                    self.state = State::CsiParam;
+                } else if is_param {
+                    self.state = if self.collect_param(c) {
+                        State::CsiParam
+                    } else {
+                        State::CsiIgnore
+                    };
+                } else if is_private {
+                    self.state = State::CsiIgnore;
                 }
             }
+            State::CsiIgnore => {
+                if is_c0 {
+                    self.execute(c);
+                } else if is_final {
+                    self.enter(State::Ground);
+                }
+            }
+            State::DcsEntry | State::DcsParam | State::DcsIntermediate => {
+                let entry = self.state == State::DcsEntry;
+                if is_final {
+                    self.dcs_final = c;
+                    self.state = State::DcsPassthrough;
+                } else if is_intermediate {
+                    self.state = if self.collect_intermediate(c) {
+                        State::DcsIntermediate
+                    } else {
+                        State::DcsIgnore
+                    };
+                } else if is_c0 || code_point == 0x7F {
+                    // ignored
+                } else if self.state == State::DcsIntermediate {
+                    self.state = State::DcsIgnore;
+                } else if is_private && entry {
+                    self.private = Some(c);
+                    self.state = State::DcsParam;
+                } else if is_param {
+                    self.state = if self.collect_param(c) {
+                        State::DcsParam
+                    } else {
+                        State::DcsIgnore
+                    };
+                } else if is_private {
+                    self.state = State::DcsIgnore;
+                }
+            }
+            State::DcsPassthrough => {
+                if code_point == 0x07 {
+                    // BEL terminates DCS strings too, like OSC strings
+                    self.end_string();
+                } else if code_point != 0x7F {
+                    self.put(c);
+                }
+            }
+            State::OscString => {
+                if code_point == 0x07 {
+                    // BEL terminates OSC strings, as in xterm
+                    self.end_string();
+                } else if !is_c0 {
+                    self.put(c);
+                }
+            }
+            State::DcsIgnore => {
+                if code_point == 0x07 {
+                    self.end_string();
+                }
+            }
+            State::SosPmApcString => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the input and returns the debug representation of the resulting actions.
+    fn parse(input: &str) -> String {
+        let mut parser = SeqParser::new();
+        parser.write(input);
+        format!("{:?}", parser.drain_actions())
+    }
+
+    #[test]
+    fn multiple_modes() {
+        assert_eq!(
+            parse("\x1b[?1;1049h"),
+            "[SetAppCursorKeys(true), SetAltBuffer(true, SaveCursor)]"
+        );
+        assert_eq!(
+            parse("\x1b[?1;1049l"),
+            "[SetAppCursorKeys(false), SetAltBuffer(false, SaveCursor)]"
+        );
+        assert_eq!(
+            parse("\x1b[?25;9999l"),
+            "[SetCursorVisible(false), Unhandled(Mode, \"\\u{1b}[?9999l\")]"
+        );
+    }
+
+    #[test]
+    fn controls_inside_sequences() {
+        // C0 controls are executed without interrupting the sequence
+        assert_eq!(
+            parse("\x1b[2\r;3\x08H"),
+            "[Return, Backspace, SetCursor(2, 1)]"
+        );
+        assert_eq!(
+            parse("\x1b(\n0"),
+            "[NewLine, SetCodePage(0, DECSpecialChars)]"
+        );
+
+        // CAN and SUB abort it
+        assert_eq!(parse("\x1b[2;3\x18H"), "[Write(\"H\")]");
+        assert_eq!(parse("\x1b[2;3\x1aH"), "[Write(\"H\")]");
+        assert_eq!(parse("\x1b]2;title\x18x"), "[Write(\"x\")]");
+        assert_eq!(parse("\x1bP$qm\x1ax"), "[Write(\"x\")]");
+
+        // ESC starts a new one
+        assert_eq!(parse("\x1b[2;3\x1b[4C"), "[MoveCursor(4, 0)]");
+    }
+
+    #[test]
+    fn non_ascii_cancels_escape() {
+        assert_eq!(parse("\x1béD"), "[Write(\"éD\")]");
+        assert_eq!(parse("\x1b(字B"), "[Write(\"字B\")]");
+    }
+
+    #[test]
+    fn private_markers_and_intermediates() {
+        // these don't apply SGR or set modes
+        for input in &["\x1b[>4;1m", "\x1b[?1m", "\x1b[=1h", "\x1b[>1h"] {
+            let actions = parse(input);
+            assert!(actions.starts_with("[Unhandled("), "{:?}", actions);
+        }
+        assert_eq!(parse("\x1b[1;2?m"), "[]");
+
+        assert_eq!(parse("\x1b[3 q"), "[SetCursorStyle(3)]");
+        assert_eq!(parse("\x1b[1\"q"), "[SetProtected(true)]");
+        assert_eq!(parse("\x1b[!p"), "[Reset(false)]");
+        assert_eq!(parse("\x1b#8"), "[ScreenAlignmentTest]");
+        assert_eq!(parse("\x1b)A"), "[SetCodePage(1, UK)]");
+
+        // too many intermediates, or parameters after them
+        assert_eq!(parse("\x1b[1 !\"q"), "[]");
+        assert_eq!(parse("\x1b[ 1q"), "[]");
+    }
+
+    #[test]
+    fn parameter_limits() {
+        assert_eq!(parse("\x1b[99999X"), "[EraseForward(65535)]");
+        assert_eq!(parse("\x1b[;5H"), "[SetCursor(4, 0)]");
+
+        let params = ["1"; MAX_PARAMS].join(";");
+        assert_eq!(
+            parse(&format!("\x1b[{}m", params)),
+            format!("{:?}", vec![Action::AddAttrs(1 << 2); MAX_PARAMS])
+        );
+        assert_eq!(parse(&format!("\x1b[{};1mx", params)), "[Write(\"x\")]");
+
+        let sub_params = ["2"; MAX_SUB_PARAMS].join(":");
+        assert_eq!(parse(&format!("\x1b[{}m", sub_params)), "[AddAttrs(512)]");
+        assert_eq!(parse(&format!("\x1b[{}:2mx", sub_params)), "[Write(\"x\")]");
+    }
+
+    #[test]
+    fn string_terminators() {
+        let title = "[SetWindowTitle(\"a\")]";
+        assert_eq!(parse("\x1b]2;a\x07"), title);
+        assert_eq!(parse("\x1b]2;a\x1b\\"), title);
+        assert_eq!(parse("\u{9d}2;a\u{9c}"), title);
+
+        let query = "[QuerySetting(\"m\")]";
+        assert_eq!(parse("\x1bP$qm\x07"), query);
+        assert_eq!(parse("\x1bP$qm\x1b\\"), query);
+        assert_eq!(parse("\u{90}$qm\u{9c}"), query);
+
+        // ignored DCS strings end the same way
+        let params = ["1"; MAX_PARAMS + 1].join(";");
+        assert_eq!(parse(&format!("\x1bP{}$qm\x07x", params)), "[Write(\"x\")]");
+        assert_eq!(
+            parse(&format!("\x1bP{}$qm\x1b\\x", params)),
+            "[Write(\"x\")]"
+        );
+    }
+
+    #[test]
+    fn string_length_limits() {
+        let title = "a".repeat(MAX_OSC_LENGTH - 2);
+        assert_eq!(
+            parse(&format!("\x1b]2;{}\x07", title)),
+            format!("[SetWindowTitle({:?})]", title)
+        );
+        assert_eq!(parse(&format!("\x1b]2;{}a\x07x", title)), "[Write(\"x\")]");
+
+        let data = "m".repeat(MAX_DCS_LENGTH);
+        assert_eq!(
+            parse(&format!("\x1bP$q{}\x1b\\", data)),
+            format!("[QuerySetting({:?})]", data)
+        );
+        assert_eq!(parse(&format!("\x1bP$q{}m\x1b\\x", data)), "[Write(\"x\")]");
+    }
+
+    #[test]
+    fn sgr_sub_parameters() {
+        assert_eq!(parse("\x1b[4:3m"), "[SetUnderline(Some(Curly))]");
+        assert_eq!(parse("\x1b[4:0m"), "[SetUnderline(None)]");
+        assert_eq!(parse("\x1b[4:m"), "[SetUnderline(Some(Single))]");
+        assert_eq!(parse("\x1b[21m"), "[SetUnderline(Some(Double))]");
+
+        // with and without the color space ID
+        let rgb = format!("{}", 0x01_02_03 + 256);
+        assert_eq!(parse("\x1b[38:2::1:2:3m"), format!("[SetColorFG({})]", rgb));
+        assert_eq!(parse("\x1b[48:2:1:2:3m"), format!("[SetColorBG({})]", rgb));
+        assert_eq!(
+            parse("\x1b[58:2:0:1:2:3m"),
+            format!("[SetColorUnderline({})]", rgb)
+        );
+        assert_eq!(parse("\x1b[58:5:100m"), "[SetColorUnderline(100)]");
+        assert_eq!(parse("\x1b[38:2:300:2:3m"), "[SetColorFG(16712451)]");
+
+        // mixed with semicolon-separated parameters
+        assert_eq!(
+            parse("\x1b[38;2;1;2;3;48:5:9;4;3m"),
+            format!(
+                "[SetColorFG({}), SetColorBG(9), SetUnderline(Some(Single)), AddAttrs(64)]",
+                rgb
+            )
+        );
+        assert_eq!(
+            parse("\x1b[58;5;200;4:5;59m"),
+            "[SetColorUnderline(200), SetUnderline(Some(Dashed)), ResetColorUnderline]"
+        );
+
+        // a color that can't be read stops the rest of a semicolon-separated list, but not
+        // the rest of a colon-separated one
+        assert_eq!(parse("\x1b[38;9;1m"), "[]");
+        assert_eq!(parse("\x1b[38:9;1m"), "[AddAttrs(4)]");
+    }
+
+    #[test]
+    fn sos_pm_apc_strings_are_discarded() {
+        assert_eq!(parse("a\x1b_payload\x1b\\b"), "[Write(\"ab\")]");
+        assert_eq!(parse("\x1bXa\r\nb\x07\x1b\\c"), "[Write(\"c\")]");
+        // any ESC ends them, like the other strings
+        assert_eq!(
+            parse("\x1b^pm\x1bP$qm\x1b\\c"),
+            "[QuerySetting(\"m\"), Write(\"c\")]"
+        );
+        assert_eq!(
+            parse("\u{98}a\u{9c}b\u{9e}c\u{9c}d\u{9f}e\u{9c}f"),
+            "[Write(\"bdf\")]"
+        );
+
+        // a string that is never terminated swallows everything until the next sequence
+        assert_eq!(
+            parse(&format!("\x1b_{}\x1b[2Jx", "a".repeat(100_000))),
+            "[ClearScreen(All, false), Write(\"x\")]"
+        );
+    }
+}
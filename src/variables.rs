@@ -49,6 +49,10 @@ pub fn defaults() -> HashMap<String, String> {
         fn_alt_mode: "1",
         show_config_links: "1",
         allow_decopt_12: "0",
+        allow_clipboard_read: "0",
+        allow_title_report: "0",
+        allow_app_resize: "0",
+        log_unhandled: "0",
         cursor_shape: "1",
         uart_baudrate: "115200",
         uart_parity: "2",